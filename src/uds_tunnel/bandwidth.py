@@ -0,0 +1,90 @@
+# -*- coding: utf-8 -*-
+#
+# Copyright (c) 2022 Virtual Cable S.L.U.
+# All rights reserved.
+#
+# Redistribution and use in source and binary forms, with or without modification,
+# are permitted provided that the following conditions are met:
+#
+#    * Redistributions of source code must retain the above copyright notice,
+#      this list of conditions and the following disclaimer.
+#    * Redistributions in binary form must reproduce the above copyright notice,
+#      this list of conditions and the following disclaimer in the documentation
+#      and/or other materials provided with the distribution.
+#    * Neither the name of Virtual Cable S.L. nor the names of its contributors
+#      may be used to endorse or promote products derived from this software
+#      without specific prior written permission.
+#
+# THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+# AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+# IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+# DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+# FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+# DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+# SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+# CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+# OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+# OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+'''
+Author: Adolfo Gómez, dkmaster at dkmon dot com
+'''
+import multiprocessing
+import time
+import typing
+import logging
+
+logger = logging.getLogger(__name__)
+
+
+class TokenBucket:
+    """
+    Token bucket for bandwidth limiting, shared by all tunnels using it.
+
+    Data already read can't be returned, so consume always succeeds, but leaves the bucket in debt.
+    The returned delay is the time the reader should stop reading so the rate is honored.
+    """
+
+    rate: float  # bytes per second
+    capacity: float  # max burst, in bytes
+    tokens: float
+    last: float  # timestamp, from time.monotonic()
+
+    def __init__(self, rate: float, capacity: typing.Optional[float] = None) -> None:
+        self.rate = rate
+        self.capacity = capacity or rate  # Defaults to one second of burst
+        self.tokens = self.capacity
+        self.last = time.monotonic()
+
+    def consume(self, size: int) -> float:
+        """Consumes size bytes from the bucket
+
+        Returns:
+            Seconds to wait before reading more data (0 if no need to wait)
+        """
+        now = time.monotonic()
+        self.tokens = min(self.capacity, self.tokens + (now - self.last) * self.rate)
+        self.last = now
+        self.tokens -= size
+        return -self.tokens / self.rate if self.tokens < 0 else 0.0
+
+
+class SharedTokenBucket(TokenBucket):
+    """
+    Token bucket shared by all worker processes, so a global limit is fairly shared by all tunnels,
+    no matter which worker is running them.
+
+    State is kept on shared memory, so it must be created before starting the workers.
+    """
+
+    _state: typing.Any  # multiprocessing.Array of (tokens, last), with its lock
+
+    def __init__(self, rate: float, capacity: typing.Optional[float] = None) -> None:
+        super().__init__(rate, capacity)
+        self._state = multiprocessing.Array('d', (self.tokens, self.last))
+
+    def consume(self, size: int) -> float:
+        with self._state.get_lock():
+            self.tokens, self.last = self._state[:]
+            delay = super().consume(size)
+            self._state[:] = (self.tokens, self.last)
+        return delay
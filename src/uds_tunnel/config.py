@@ -47,13 +47,19 @@ class ConfigurationType(typing.NamedTuple):
     logfile: str
     logsize: int
     lognumber: int
+    logformat: str  # Valid values are text and json
 
     listen_address: str
     listen_port: int
+    bind_retries: int
 
     ipv6: bool
 
     workers: int
+    max_connections: int
+    shutdown_grace: float
+    bandwidth_limit: int  # bytes per second, for all tunnels. 0 means no limit
+    tunnel_bandwidth_limit: int  # bytes per second, for each tunnel. 0 means no limit
 
     ssl_min_tls_version: str  # Valid values are 1.2, 1.3 (1.0 and 1.1 are not supported)
     ssl_certificate: str
@@ -62,16 +68,43 @@ class ConfigurationType(typing.NamedTuple):
     ssl_ciphers: str
     ssl_dhparam: str
 
-    uds_server: str
+    uds_backend: str  # Valid values are http and file (uds_server is a json file with tickets, for testing)
+    uds_server: str  # First of uds_servers
+    uds_servers: typing.Tuple[str, ...]  # Failover order. Unreachable ones are skipped for a while
     uds_token: str
     uds_timeout: int
     uds_verify_ssl: bool
+    uds_cert_fingerprint: str  # SHA-256 of uds server certificate, lower case hex. Empty means not pinned
+    uds_client_certificate: str  # Client certificate presented to uds server. Empty means none
+    uds_client_key: str
+    uds_retries: int
+    uds_retry_delay: float
+    uds_max_pending: int
+    uds_max_response_size: int
+    uds_ticket_cache_ttl: float  # Seconds a broker response is reused for same ticket and source. 0 disables it
 
     command_timeout: float
 
+    handshake_max_failures: int
+    handshake_failures_window: float
+    handshake_ban_time: float
+    write_timeout: float
+    keepalive_interval: int  # Idle seconds before checking tunnel peers are alive. 0 disables it
+    keepalive_timeout: int  # Seconds without answer to consider a peer dead
+
     secret: str
     allow: typing.Set[str]
 
+    allowed_ports: typing.Set[int]
+    require_ip_remotes: bool
+    happy_eyeballs_delay: float  # Delay between connection attempts to each address of a destination. 0 disables it
+    connect_timeout: float  # Max seconds to connect to a destination. 0 means no limit (system default)
+
+    metrics_address: str
+    metrics_port: int
+
+    log_destination: str  # Valid values are full, redacted (host only) and none
+
     use_uvloop: bool
 
     def __str__(self) -> str:
@@ -104,18 +137,39 @@ def read(cfg_file: typing.Optional[typing.Union[typing.TextIO, str]] = None) ->
     h.update(uds.get('secret', '').encode())
     secret = h.hexdigest()
 
-    # Now load and fix uds server url
-    uds_server = uds['uds_server']
-    if uds_server[:4] != 'http':
-        raise Exception('Invalid url for uds server')
-    if uds_server[-1] == '/':
-        uds_server = uds_server[:-1]
+    # Now load and fix uds server url (or tickets file path)
+    uds_backend = uds.get('uds_backend', 'http').lower()
+    if uds_backend not in ('http', 'file'):
+        raise Exception(f'Invalid uds backend: {uds_backend}')
+    uds_servers: typing.Tuple[str, ...] = (uds['uds_server'],)
+    if uds_backend == 'http':
+        # Several servers can be used, separated by commas
+        uds_servers = tuple(url.strip().rstrip('/') for url in uds['uds_server'].split(',') if url.strip())
+        if not uds_servers or any(url[:4] != 'http' for url in uds_servers):
+            raise Exception('Invalid url for uds server')
+    uds_server = uds_servers[0]
 
     try:
         # log size
         logsize: str = uds.get('logsize', '32M')
         if logsize[-1] == 'M':
             logsize = logsize[:-1]
+        uds_cert_fingerprint = uds.get('uds_cert_fingerprint', '').replace(':', '').strip().lower()
+        if uds_cert_fingerprint and len(bytes.fromhex(uds_cert_fingerprint)) != 32:
+            raise ValueError(f'Invalid uds_cert_fingerprint (not a SHA-256): {uds_cert_fingerprint}')
+        uds_client_certificate = uds.get('uds_client_certificate', '')
+        uds_client_key = uds.get('uds_client_key', '')
+        if bool(uds_client_certificate) != bool(uds_client_key):
+            raise ValueError('uds_client_certificate and uds_client_key must be both set')
+        logformat = uds.get('logformat', 'text').lower()
+        if logformat not in ('text', 'json'):
+            raise ValueError(f'Invalid logformat: {logformat}')
+        log_destination = uds.get('log_destination', 'full').lower()
+        if log_destination not in ('full', 'redacted', 'none'):
+            raise ValueError(f'Invalid log_destination: {log_destination}')
+        allowed_ports = {int(port) for port in uds.get('allowed_ports', '').split(',') if port.strip()}
+        if any(not 1 <= port <= 65535 for port in allowed_ports):
+            raise ValueError(f'Invalid allowed_ports (must be between 1 and 65535): {sorted(allowed_ports)}')
         return ConfigurationType(
             pidfile=uds.get('pidfile', ''),
             user=uds.get('user', ''),
@@ -123,23 +177,52 @@ def read(cfg_file: typing.Optional[typing.Union[typing.TextIO, str]] = None) ->
             logfile=uds.get('logfile', ''),
             logsize=int(logsize) * 1024 * 1024,
             lognumber=int(uds.get('lognumber', '3')),
+            logformat=logformat,
             listen_address=uds.get('address', '0.0.0.0'),
             listen_port=int(uds.get('port', '443')),
+            bind_retries=int(uds.get('bind_retries', '0')),
             ipv6=uds.get('ipv6', 'false').lower() == 'true',
             workers=int(uds.get('workers', '0')) or multiprocessing.cpu_count(),
+            max_connections=int(uds.get('max_connections', '0')),
+            shutdown_grace=float(uds.get('shutdown_grace', '0')),
+            bandwidth_limit=int(uds.get('bandwidth_limit', '0')),
+            tunnel_bandwidth_limit=int(uds.get('tunnel_bandwidth_limit', '0')),
             ssl_min_tls_version=uds.get('ssl_min_tls_version', '1.2'),
             ssl_certificate=uds['ssl_certificate'],
             ssl_certificate_key=uds.get('ssl_certificate_key', ''),
             ssl_password=uds.get('ssl_password', ''),
             ssl_ciphers=uds.get('ssl_ciphers'),
             ssl_dhparam=uds.get('ssl_dhparam'),
+            uds_backend=uds_backend,
             uds_server=uds_server,
+            uds_servers=uds_servers,
             uds_token=uds.get('uds_token', 'unauthorized'),
             uds_timeout=int(uds.get('uds_timeout', '10')),
             uds_verify_ssl=uds.get('uds_verify_ssl', 'true').lower() == 'true',
+            uds_cert_fingerprint=uds_cert_fingerprint,
+            uds_client_certificate=uds_client_certificate,
+            uds_client_key=uds_client_key,
+            uds_retries=int(uds.get('uds_retries', '0')),
+            uds_retry_delay=float(uds.get('uds_retry_delay', '0.25')),
+            uds_max_pending=int(uds.get('uds_max_pending', '0')),
+            uds_max_response_size=int(uds.get('uds_max_response_size', '65536')),
+            uds_ticket_cache_ttl=float(uds.get('uds_ticket_cache_ttl', '0')),
             command_timeout=float(uds.get('command_timeout', '3')),
+            write_timeout=float(uds.get('write_timeout', '0')),
+            keepalive_interval=int(uds.get('keepalive_interval', '0')),
+            keepalive_timeout=int(uds.get('keepalive_timeout', '30')),
+            handshake_max_failures=int(uds.get('handshake_max_failures', '0')),
+            handshake_failures_window=float(uds.get('handshake_failures_window', '60')),
+            handshake_ban_time=float(uds.get('handshake_ban_time', '300')),
             secret=secret,
             allow=set(uds.get('allow', '127.0.0.1').split(',')),
+            allowed_ports=allowed_ports,
+            require_ip_remotes=uds.get('require_ip_remotes', 'false').lower() == 'true',
+            happy_eyeballs_delay=float(uds.get('happy_eyeballs_delay', '0')),
+            connect_timeout=float(uds.get('connect_timeout', '0')),
+            metrics_address=uds.get('metrics_address', ''),
+            metrics_port=int(uds.get('metrics_port', '9109')),
+            log_destination=log_destination,
             use_uvloop=uds.get('use_uvloop', 'true').lower() == 'true',
         )
     except ValueError as e:
@@ -62,16 +62,44 @@ COMMAND_OPEN: typing.Final[bytes] = b'OPEN'
 COMMAND_TEST: typing.Final[bytes] = b'TEST'
 COMMAND_STAT: typing.Final[bytes] = b'STAT'  # full stats
 COMMAND_INFO: typing.Final[bytes] = b'INFO'  # Basic stats, currently same as FULL
+# Max length of each command with its data (ticket or password). Longer ones are malformed
+COMMAND_MAX_LENGTH: typing.Final[typing.Mapping[bytes, int]] = {
+    COMMAND_OPEN: COMMAND_LENGTH + TICKET_LENGTH,
+    COMMAND_TEST: COMMAND_LENGTH,
+    COMMAND_STAT: COMMAND_LENGTH + PASSWORD_LENGTH,
+    COMMAND_INFO: COMMAND_LENGTH + PASSWORD_LENGTH,
+}
 
 RESPONSE_ERROR_TICKET: typing.Final[bytes] = b'ERROR_TICKET'
 RESPONSE_ERROR_COMMAND: typing.Final[bytes] = b'ERROR_COMMAND'
 RESPONSE_ERROR_TIMEOUT: typing.Final[bytes] = b'TIMEOUT'
 RESPONSE_FORBIDDEN: typing.Final[bytes] = b'FORBIDDEN'
+RESPONSE_BUSY: typing.Final[bytes] = b'BUSY'
 
 RESPONSE_OK: typing.Final[bytes] = b'OK'
 
+# Broker HTTP status codes that can be retried (broker temporarily unavailable)
+BROKER_RETRYABLE_STATUS: typing.Final[typing.FrozenSet[int]] = frozenset((502, 503, 504))
+
+# Seconds an unreachable uds server is skipped, if there are other ones
+BROKER_DOWN_TIME: typing.Final[float] = 30.0
+
+# Extra seconds given to workers on stop, over shutdown_grace and uds_timeout, to notice the stop
+# and to close the tunnels still running before being killed
+SHUTDOWN_MARGIN: typing.Final[float] = 3.0
+
 # Backlog for listen socket
 BACKLOG = 1024
+# Delay between listen socket bind retries
+BIND_RETRY_DELAY: typing.Final[float] = 1.0
+
+# Max number of broker responses kept on ticket cache (per worker process)
+TICKET_CACHE_MAX_ENTRIES: typing.Final[int] = 4096
+
+# Exit code used when the listen socket cannot be bound
+EXIT_BIND_ERROR: typing.Final[int] = 3
+# Exit code used when configured files (as uds client certificate) cannot be loaded
+EXIT_CONFIG_ERROR: typing.Final[int] = 4
 
 # Regular expression for parsing ticket
 TICKET_REGEX = re.compile(f'^[a-zA-Z0-9]{{{TICKET_LENGTH}}}$')
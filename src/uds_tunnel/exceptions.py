@@ -0,0 +1,84 @@
+# -*- coding: utf-8 -*-
+#
+# Copyright (c) 2022 Virtual Cable S.L.U.
+# All rights reserved.
+#
+# Redistribution and use in source and binary forms, with or without modification,
+# are permitted provided that the following conditions are met:
+#
+#    * Redistributions of source code must retain the above copyright notice,
+#      this list of conditions and the following disclaimer.
+#    * Redistributions in binary form must reproduce the above copyright notice,
+#      this list of conditions and the following disclaimer in the documentation
+#      and/or other materials provided with the distribution.
+#    * Neither the name of Virtual Cable S.L. nor the names of its contributors
+#      may be used to endorse or promote products derived from this software
+#      without specific prior written permission.
+#
+# THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+# AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+# IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+# DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+# FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+# DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+# SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+# CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+# OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+# OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+'''
+Author: Adolfo Gómez, dkmaster at dkmon dot com
+'''
+from . import consts
+
+
+class BrokerError(Exception):
+    """Error on communications with UDS broker
+
+    Args:
+        message: Error message
+        retryable: If the request can be retried (network errors, broker temporarily unavailable)
+    """
+
+    retryable: bool
+
+    def __init__(self, message: str, *, retryable: bool = False) -> None:
+        super().__init__(message)
+        self.retryable = retryable
+
+
+class BrokerNotFoundError(BrokerError):
+    """Ticket not found on broker (404)"""
+
+
+class BrokerUnauthorizedError(BrokerError):
+    """Broker refused our token or the ticket (401, 403)"""
+
+
+class BrokerUnavailableError(BrokerError):
+    """Broker could not be reached or failed processing the request (network errors, 5xx)"""
+
+
+class BrokerDecodeError(BrokerError):
+    """Broker response is not valid"""
+
+
+class BrokerResponseTooLargeError(BrokerDecodeError):
+    """Broker response is bigger than allowed (uds_max_response_size)"""
+
+
+class BrokerTimeoutError(BrokerError):
+    """Broker did not answer in time (uds_timeout)"""
+
+    def __init__(self, message: str) -> None:
+        super().__init__(message, retryable=True)
+
+
+def broker_error_for_status(status: int, message: str) -> BrokerError:
+    """Returns the BrokerError matching a broker http error status"""
+    if status == 404:
+        return BrokerNotFoundError(message)
+    if status in (401, 403):
+        return BrokerUnauthorizedError(message)
+    if status >= 500:
+        return BrokerUnavailableError(message, retryable=status in consts.BROKER_RETRYABLE_STATUS)
+    return BrokerError(message)
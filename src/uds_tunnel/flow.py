@@ -0,0 +1,127 @@
+# -*- coding: utf-8 -*-
+#
+# Copyright (c) 2022 Virtual Cable S.L.U.
+# All rights reserved.
+#
+# Redistribution and use in source and binary forms, with or without modification,
+# are permitted provided that the following conditions are met:
+#
+#    * Redistributions of source code must retain the above copyright notice,
+#      this list of conditions and the following disclaimer.
+#    * Redistributions in binary form must reproduce the above copyright notice,
+#      this list of conditions and the following disclaimer in the documentation
+#      and/or other materials provided with the distribution.
+#    * Neither the name of Virtual Cable S.L. nor the names of its contributors
+#      may be used to endorse or promote products derived from this software
+#      without specific prior written permission.
+#
+# THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+# AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+# IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+# DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+# FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+# DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+# SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+# CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+# OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+# OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+'''
+Author: Adolfo Gómez, dkmaster at dkmon dot com
+'''
+import asyncio
+import typing
+import logging
+
+logger = logging.getLogger(__name__)
+
+
+class FlowControl:
+    """
+    Flow control shared by both sides of a tunnel (mixed in their protocols).
+
+    Reading of a side may be paused for several reasons at once (tunnel not open yet,
+    bandwidth throttling, backpressure), and is only resumed once no reason is left.
+    While our write buffer is full, the other side of the tunnel stops reading, and the
+    tunnel is closed if the buffer does not drain at all for write_timeout seconds.
+    """
+
+    transport: 'asyncio.transports.Transport'
+    # Reasons the reading of this side is paused for
+    read_paused_by: typing.Set[str]
+    # Write watchdog, armed while our transport write buffer is full
+    write_watchdog: typing.Optional[asyncio.TimerHandle] = None
+    # Write buffer size when watchdog was armed, to know if it is draining
+    write_watchdog_size: int = 0
+    # Pending resume of reading, if paused by bandwidth limits
+    unthrottle_handle: typing.Optional[asyncio.TimerHandle] = None
+
+    def __init__(self) -> None:
+        super().__init__()
+        self.read_paused_by = set()
+
+    @property
+    def peer(self) -> typing.Optional['FlowControl']:
+        """Other side of the tunnel, if already connected"""
+        raise NotImplementedError()
+
+    @property
+    def write_timeout(self) -> float:
+        raise NotImplementedError()
+
+    def write_stalled(self) -> None:
+        raise NotImplementedError()
+
+    def pause_reading(self, reason: str) -> None:
+        if not self.read_paused_by and not self.transport.is_closing():
+            self.transport.pause_reading()
+        self.read_paused_by.add(reason)
+
+    def resume_reading(self, reason: str) -> None:
+        if reason not in self.read_paused_by:
+            return
+        self.read_paused_by.remove(reason)
+        if not self.read_paused_by and not self.transport.is_closing():
+            self.transport.resume_reading()
+
+    def throttle(self, delay: float) -> None:
+        """Pauses reading for delay seconds, replacing any previous throttle delay"""
+        if self.unthrottle_handle:
+            self.unthrottle_handle.cancel()
+        self.pause_reading('throttle')
+        self.unthrottle_handle = asyncio.get_running_loop().call_later(delay, self.unthrottle)
+
+    def unthrottle(self) -> None:
+        self.unthrottle_handle = None
+        self.resume_reading('throttle')
+
+    def pause_writing(self) -> None:
+        # Do not read more than we can write
+        if self.peer:
+            self.peer.pause_reading('backpressure')
+        self.start_write_watchdog()
+
+    def resume_writing(self) -> None:
+        self.stop_write_watchdog()
+        if self.peer:
+            self.peer.resume_reading('backpressure')
+
+    def start_write_watchdog(self) -> None:
+        """Arms the write watchdog, if configured.
+        If no data is flushed in write_timeout seconds, the connection is considered stalled.
+        """
+        if self.write_timeout and not self.write_watchdog:
+            self.write_watchdog_size = self.transport.get_write_buffer_size()
+            self.write_watchdog = asyncio.get_running_loop().call_later(self.write_timeout, self.check_write)
+
+    def stop_write_watchdog(self) -> None:
+        if self.write_watchdog:
+            self.write_watchdog.cancel()
+            self.write_watchdog = None
+
+    def check_write(self) -> None:
+        self.write_watchdog = None
+        # A slow writer is fine while it keeps draining the buffer
+        if self.transport.get_write_buffer_size() < self.write_watchdog_size:
+            self.start_write_watchdog()
+        else:
+            self.write_stalled()
@@ -0,0 +1,188 @@
+# -*- coding: utf-8 -*-
+#
+# Copyright (c) 2022 Virtual Cable S.L.U.
+# All rights reserved.
+#
+# Redistribution and use in source and binary forms, with or without modification,
+# are permitted provided that the following conditions are met:
+#
+#    * Redistributions of source code must retain the above copyright notice,
+#      this list of conditions and the following disclaimer.
+#    * Redistributions in binary form must reproduce the above copyright notice,
+#      this list of conditions and the following disclaimer in the documentation
+#      and/or other materials provided with the distribution.
+#    * Neither the name of Virtual Cable S.L. nor the names of its contributors
+#      may be used to endorse or promote products derived from this software
+#      without specific prior written permission.
+#
+# THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+# AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+# IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+# DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+# FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+# DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+# SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+# CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+# OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+# OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+'''
+Author: Adolfo Gómez, dkmaster at dkmon dot com
+'''
+import hashlib
+import http.client
+import http.server
+import os
+import socket
+import ssl
+import threading
+import typing
+import logging
+import urllib.parse
+
+if typing.TYPE_CHECKING:
+    from multiprocessing.managers import Namespace
+
+    from . import config
+
+logger = logging.getLogger(__name__)
+
+# (name, type, help, namespace counter)
+METRICS: typing.Final[typing.List[typing.Tuple[str, str, str, str]]] = [
+    ('uds_tunnel_active_sessions', 'gauge', 'Currently open tunnels', 'current'),
+    ('uds_tunnel_sessions_total', 'counter', 'Tunnels opened since start', 'total'),
+    ('uds_tunnel_bytes_sent_total', 'counter', 'Bytes sent from clients to destinations', 'sent'),
+    ('uds_tunnel_bytes_received_total', 'counter', 'Bytes received from destinations', 'recv'),
+    ('uds_tunnel_handshake_failures_total', 'counter', 'Failed handshakes (invalid data, wrong tickets or command timeouts)', 'handshake_failures'),
+    ('uds_tunnel_broker_errors_total', 'counter', 'Failed ticket requests to broker', 'broker_errors'),
+    ('uds_tunnel_invalid_commands_total', 'counter', 'Unknown commands received', 'invalid_commands'),
+    ('uds_tunnel_malformed_commands_total', 'counter', 'Commands with more data than allowed', 'malformed_commands'),
+]
+
+
+def get_metrics(ns: 'Namespace') -> str:
+    """Returns the global stats in Prometheus text exposition format"""
+    lines: typing.List[str] = []
+    for name, kind, description, counter in METRICS:
+        lines.append(f'# HELP {name} {description}')
+        lines.append(f'# TYPE {name} {kind}')
+        lines.append(f'{name} {getattr(ns, counter, 0)}')
+
+    # Broker latency, as a summary (sum and count) of ticket requests
+    lines.append('# HELP uds_tunnel_broker_latency_seconds Time spent waiting for broker ticket responses')
+    lines.append('# TYPE uds_tunnel_broker_latency_seconds summary')
+    lines.append(f'uds_tunnel_broker_latency_seconds_sum {getattr(ns, "broker_latency", 0.0)}')
+    lines.append(f'uds_tunnel_broker_latency_seconds_count {getattr(ns, "broker_requests", 0)}')
+    return '\n'.join(lines) + '\n'
+
+
+def broker_reachable(
+    url: str,
+    verify_ssl: bool,
+    timeout: float,
+    context: typing.Optional[ssl.SSLContext] = None,
+    fingerprint: str = '',
+) -> bool:
+    """Checks if broker answers http requests (any http response, even errors, means reachable)
+    If fingerprint is given (as uds_cert_fingerprint), broker certificate must match it instead of being verified
+    """
+    parsed = urllib.parse.urlsplit(url)
+    conn: http.client.HTTPConnection
+    if parsed.scheme == 'https':
+        if context is None:  # Not using a client certificate
+            context = ssl.create_default_context()
+            if not verify_ssl or fingerprint:
+                context.check_hostname = False
+                context.verify_mode = ssl.CERT_NONE
+        conn = http.client.HTTPSConnection(parsed.netloc, timeout=timeout, context=context)
+    else:
+        conn = http.client.HTTPConnection(parsed.netloc, timeout=timeout)
+    try:
+        conn.connect()
+        if fingerprint and isinstance(conn.sock, ssl.SSLSocket):
+            cert = conn.sock.getpeercert(binary_form=True) or b''
+            if hashlib.sha256(cert).hexdigest() != fingerprint:
+                logger.warning('BROKER certificate at %s does not match uds_cert_fingerprint', url)
+                return False
+        conn.request('GET', parsed.path or '/')
+        conn.getresponse()
+        return True
+    except Exception as e:
+        logger.warning('BROKER not reachable at %s: %s', url, e)
+        return False
+    finally:
+        conn.close()
+
+
+def broker_ready(cfg: 'config.ConfigurationType', context: typing.Optional[ssl.SSLContext] = None) -> bool:
+    """Checks if tickets can be resolved, by any of the uds servers or by the tickets file"""
+    if cfg.uds_backend == 'file':
+        return os.access(cfg.uds_server, os.R_OK)
+    return any(
+        broker_reachable(url, cfg.uds_verify_ssl, cfg.uds_timeout, context, cfg.uds_cert_fingerprint)
+        for url in cfg.uds_servers
+    )
+
+
+class MetricsServer(http.server.ThreadingHTTPServer):
+    daemon_threads = True
+    ns: 'Namespace'
+    ready_check: typing.Optional[typing.Callable[[], bool]]
+
+    def __init__(
+        self,
+        address: typing.Tuple[str, int],
+        ns: 'Namespace',
+        ready_check: typing.Optional[typing.Callable[[], bool]] = None,
+    ) -> None:
+        if ':' in address[0]:
+            self.address_family = socket.AF_INET6
+        self.ns = ns
+        self.ready_check = ready_check
+        super().__init__(address, MetricsHandler)
+
+    def start(self) -> None:
+        """Serves requests on a background (daemon) thread"""
+        threading.Thread(target=self.serve_forever, name='metrics', daemon=True).start()
+
+    def stop(self) -> None:
+        self.shutdown()
+        self.server_close()
+
+
+class MetricsHandler(http.server.BaseHTTPRequestHandler):
+    server: MetricsServer
+
+    def do_GET(self) -> None:  # pylint: disable=invalid-name
+        if self.path == '/healthz':
+            # If we are answering, listener is bound and config is fine
+            self.reply(b'OK\n')
+        elif self.path == '/ready':
+            try:
+                ready = self.server.ready_check() if self.server.ready_check else True
+            except Exception as e:
+                logger.error('READY check error: %s', e)
+                ready = False
+            if ready:
+                self.reply(b'READY\n')
+            else:
+                self.send_error(503)
+        elif self.path == '/metrics':
+            try:
+                body = get_metrics(self.server.ns).encode()
+            except Exception as e:
+                logger.error('METRICS error: %s', e)
+                self.send_error(500)
+                return
+            self.reply(body, 'text/plain; version=0.0.4')
+        else:
+            self.send_error(404)
+
+    def reply(self, body: bytes, content_type: str = 'text/plain') -> None:
+        self.send_response(200)
+        self.send_header('Content-Type', content_type)
+        self.send_header('Content-Length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, format: str, *args: typing.Any) -> None:  # pylint: disable=redefined-builtin
+        logger.debug('METRICS %s: %s', self.address_string(), format % args)
@@ -31,21 +31,25 @@ Author: Adolfo Gómez, dkmaster at dkmon dot com
 import multiprocessing
 import asyncio
 import sys
+import time
 import logging
 import typing
 
 import psutil
 
 from . import config
+from . import consts
 
 if typing.TYPE_CHECKING:
     from multiprocessing.connection import Connection
     from multiprocessing.managers import Namespace
 
+    from . import bandwidth
+
 logger = logging.getLogger(__name__)
 
 ProcessType = typing.Callable[
-    ['Connection', config.ConfigurationType, 'Namespace'],
+    ['Connection', config.ConfigurationType, 'Namespace', typing.Optional['bandwidth.TokenBucket']],
     typing.Coroutine[typing.Any, None, None],
 ]
 
@@ -63,14 +67,20 @@ class Processes:
     process: ProcessType
     cfg: config.ConfigurationType
     ns: 'Namespace'
+    bandwidth: typing.Optional['bandwidth.TokenBucket']  # Global bandwidth limit, shared by all children
 
     def __init__(
-        self, process: ProcessType, cfg: config.ConfigurationType, ns: 'Namespace'
+        self,
+        process: ProcessType,
+        cfg: config.ConfigurationType,
+        ns: 'Namespace',
+        bandwidth: typing.Optional['bandwidth.TokenBucket'] = None,  # pylint: disable=redefined-outer-name
     ) -> None:
         self.children = []
         self.process = process  # type: ignore
         self.cfg = cfg
         self.ns = ns
+        self.bandwidth = bandwidth
 
         for _ in range(cfg.workers):
             self.add_child_pid()
@@ -79,7 +89,7 @@ class Processes:
         own_conn, child_conn = multiprocessing.Pipe()
         task = multiprocessing.Process(
             target=Processes.runner,
-            args=(self.process, child_conn, self.cfg, self.ns),
+            args=(self.process, child_conn, self.cfg, self.ns, self.bandwidth),
         )
         task.start()
         logger.debug('ADD CHILD PID: %s', task.pid)
@@ -140,7 +150,30 @@ class Processes:
 
         return best[1]
 
-    def stop(self) -> None:
+    def failed_sources(self) -> typing.List[str]:
+        """Returns the sources reported as failed by children (wrong tickets, timeouts) since last call"""
+        sources: typing.List[str] = []
+        for c in self.children:
+            try:
+                while c[0].poll():
+                    sources.append(c[0].recv())
+            except (EOFError, OSError):
+                pass  # Missing process, best_child will replace it
+        return sources
+
+    def stop(self, grace: float = 0) -> None:
+        # If grace is given, ask children to stop (SIGTERM), so they can finish running tunnels
+        if grace:
+            for i in self.children:
+                try:
+                    i[1].terminate()
+                except Exception as e:
+                    logger.info('TERMINATING child %s: %s', i[2], e)
+            # Tunnels still running after grace are closed, and broker notified of it (bounded by uds_timeout)
+            deadline = time.monotonic() + grace + self.cfg.uds_timeout + consts.SHUTDOWN_MARGIN
+            for i in self.children:
+                i[1].join(max(0, deadline - time.monotonic()))
+
         # Try to stop running childs
         for i in self.children:
             try:
@@ -154,6 +187,7 @@ class Processes:
         conn: 'Connection',
         cfg: config.ConfigurationType,
         ns: 'Namespace',
+        bandwidth: typing.Optional['bandwidth.TokenBucket'] = None,  # pylint: disable=redefined-outer-name
     ) -> None:
         if cfg.use_uvloop:
             try:
@@ -161,12 +195,12 @@ class Processes:
 
                 if sys.version_info >= (3, 11):
                     with asyncio.Runner(loop_factory=uvloop.new_event_loop) as runner:
-                        runner.run(proc(conn, cfg, ns))
+                        runner.run(proc(conn, cfg, ns, bandwidth))
                 else:
                     uvloop.install()
-                    asyncio.run(proc(conn, cfg, ns))
+                    asyncio.run(proc(conn, cfg, ns, bandwidth))
             except ImportError:
                 logger.warning('uvloop not found, using default asyncio')
-                asyncio.run(proc(conn, cfg, ns))
+                asyncio.run(proc(conn, cfg, ns, bandwidth))
         else:
-            asyncio.run(proc(conn, cfg, ns))
+            asyncio.run(proc(conn, cfg, ns, bandwidth))
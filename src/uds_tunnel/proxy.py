@@ -34,12 +34,15 @@ import logging
 import typing
 
 from . import config
+from . import log
 from . import tunnel
 
 if typing.TYPE_CHECKING:
     from multiprocessing.managers import Namespace
     import ssl
 
+    from . import bandwidth
+
 logger = logging.getLogger(__name__)
 
 
@@ -47,11 +50,29 @@ class Proxy:
     cfg: 'config.ConfigurationType'
     ns: 'Namespace'
     finished: asyncio.Event
+    notify_task: typing.Optional['asyncio.Task[None]']  # Stop notification to broker, if any
+    bandwidth: typing.Optional['bandwidth.TokenBucket']  # Shared by all tunnels (of all workers), if limited
+    report_failure: typing.Optional[typing.Callable[[str], None]]  # Sends failed sources to handshake limiter, if any
 
-    def __init__(self, cfg: 'config.ConfigurationType', ns: 'Namespace') -> None:
+    def __init__(
+        self,
+        cfg: 'config.ConfigurationType',
+        ns: 'Namespace',
+        bandwidth: typing.Optional['bandwidth.TokenBucket'] = None,  # pylint: disable=redefined-outer-name
+        report_failure: typing.Optional[typing.Callable[[str], None]] = None,
+    ) -> None:
         self.cfg = cfg
         self.ns = ns
+        self.bandwidth = bandwidth
+        self.report_failure = report_failure
         self.finished = asyncio.Event()
+        self.notify_task = None
+
+    def add_failure(self, ip: str) -> None:
+        """Counts a failed handshake (wrong ticket, command timeout) from ip"""
+        self.ns.handshake_failures += 1
+        if self.report_failure:
+            self.report_failure(ip)
 
     # Method responsible of proxying requests
     async def __call__(self, source: socket.socket, context: 'ssl.SSLContext') -> None:
@@ -76,22 +97,38 @@ class Proxy:
 
         # Store source ip and port, for logging purposes in case of error
         src_ip, src_port = (source.getpeername() if source else ('Unknown', 0))[:2]   # May be ipv4 or ipv6, so we get only first two elements
+        # Every log of this tunnel (this task and its callbacks) will include source ip if structured
+        log.source_ip.set(src_ip)
 
         # Upgrade connection to SSL, and use asyncio to handle the rest
         tun: typing.Optional[tunnel.TunnelProtocol] = None
+        transport: typing.Optional[asyncio.BaseTransport] = None
         try:
             tun = tunnel.TunnelProtocol(self)
             # (connect accepted loop not present on AbastractEventLoop definition < 3.10), that's why we use ignore
-            await loop.connect_accepted_socket(  # type: ignore
+            transport, _ = await loop.connect_accepted_socket(  # type: ignore
                 lambda: tun, source, ssl=context,
                 ssl_handshake_timeout=3,
             )
 
             # Wait for connection to be closed
             await self.finished.wait()
+            # And for broker to be notified (bounded by uds_timeout). Shielded, so a cancel does not lose it
+            # If closed while asking broker for the ticket, notification is only known once it answers
+            if tun.open_task:
+                await asyncio.shield(tun.open_task)
+            if self.notify_task:
+                await asyncio.shield(self.notify_task)
 
         except asyncio.CancelledError:
-            pass  # Return on cancel
+            # Stopping, close the tunnel but still let the broker know about it
+            if transport and not self.finished.is_set():
+                transport.abort()
+                await self.finished.wait()
+            if tun and tun.open_task:
+                await tun.open_task
+            if self.notify_task:
+                await self.notify_task
         except Exception as e:
             # Any other exception, ensure we close the connection
             logger.error('ERROR on %s:%s: %s', src_ip, src_port, e)
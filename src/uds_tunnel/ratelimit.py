@@ -0,0 +1,109 @@
+# -*- coding: utf-8 -*-
+#
+# Copyright (c) 2022 Virtual Cable S.L.U.
+# All rights reserved.
+#
+# Redistribution and use in source and binary forms, with or without modification,
+# are permitted provided that the following conditions are met:
+#
+#    * Redistributions of source code must retain the above copyright notice,
+#      this list of conditions and the following disclaimer.
+#    * Redistributions in binary form must reproduce the above copyright notice,
+#      this list of conditions and the following disclaimer in the documentation
+#      and/or other materials provided with the distribution.
+#    * Neither the name of Virtual Cable S.L. nor the names of its contributors
+#      may be used to endorse or promote products derived from this software
+#      without specific prior written permission.
+#
+# THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+# AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+# IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+# DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+# FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+# DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+# SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+# CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+# OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+# OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+'''
+Author: Adolfo Gómez, dkmaster at dkmon dot com
+'''
+import ipaddress
+import threading
+import time
+import typing
+import logging
+
+logger = logging.getLogger(__name__)
+
+
+def canonical_ip(ip: str) -> str:
+    """Returns the canonical form of an ip, so all representations of an address share the same key
+
+    IPv6 is normalized (compressed, lower case, no zone id) and IPv4 mapped IPv6 addresses are
+    returned as plain IPv4. Anything that is not an ip is returned unchanged.
+    """
+    try:
+        address = ipaddress.ip_address(ip.split('%', 1)[0])
+    except ValueError:
+        return ip
+    if isinstance(address, ipaddress.IPv6Address) and address.ipv4_mapped:
+        address = address.ipv4_mapped
+    return str(address)
+
+
+class HandshakeLimiter:
+    """
+    Keeps track of failed handshakes per source ip, so sources with too many failures
+    can be refused before reading anything from them.
+
+    Used from several threads (handshake processing pool), so it is protected by a lock.
+    Ips are canonicalized, so an address can't evade limits by changing its representation.
+    """
+
+    max_failures: int
+    window: float
+    ban_time: float
+
+    _failures: typing.Dict[str, typing.List[float]]  # ip -> failure timestamps inside window
+    _banned: typing.Dict[str, float]  # ip -> ban expiration timestamp
+    _last_cleanup: float
+    _lock: threading.Lock
+
+    def __init__(self, max_failures: int, window: float, ban_time: float) -> None:
+        self.max_failures = max_failures
+        self.window = window
+        self.ban_time = ban_time
+        self._failures = {}
+        self._banned = {}
+        self._last_cleanup = time.monotonic()
+        self._lock = threading.Lock()
+
+    def is_banned(self, ip: str) -> bool:
+        ip = canonical_ip(ip)
+        now = time.monotonic()
+        with self._lock:
+            self._cleanup(now)
+            return self._banned.get(ip, 0) > now
+
+    def add_failure(self, ip: str) -> None:
+        ip = canonical_ip(ip)
+        now = time.monotonic()
+        with self._lock:
+            failures = [t for t in self._failures.get(ip, []) if now - t < self.window]
+            failures.append(now)
+            if len(failures) >= self.max_failures:
+                logger.warning('BANNED %s for %s seconds: too many failed handshakes', ip, self.ban_time)
+                self._banned[ip] = now + self.ban_time
+                failures = []
+            self._failures[ip] = failures
+
+    def _cleanup(self, now: float) -> None:
+        # Remove idle entries, at most once per window
+        if now - self._last_cleanup < self.window:
+            return
+        self._last_cleanup = now
+        self._failures = {
+            ip: failures for ip, failures in self._failures.items() if failures and now - failures[-1] < self.window
+        }
+        self._banned = {ip: until for ip, until in self._banned.items() if until > now}
@@ -71,9 +71,11 @@ class StatsManager:
     last: float  # timestamp, from time.monotonic()
     start_time: float  # timestamp, from time.monotonic()
     end_time: float  # timestamp, from time.monotonic()
+    counted: bool  # If this connection has been added to current connections
 
     def __init__(self, ns: 'Namespace'):
         self.ns = ns
+        self.counted = False
         self.sent = self.last_sent = 0
         self.recv = self.last_recv = 0
         self.last = time.monotonic()
@@ -102,12 +104,15 @@ class StatsManager:
         self.update()
 
     def decrement_connections(self):
-        # Decrement current runing connections
-        self.ns.current -= 1
+        # Decrement current runing connections, only if this one was counted
+        if self.counted:
+            self.counted = False
+            self.ns.current -= 1
 
     def increment_connections(self):
         # Increment current runing connections
         # Also, increment total connections
+        self.counted = True
         self.ns.current += 1
         self.ns.total += 1
 
@@ -141,6 +146,12 @@ class GlobalStats:
         self.ns.total = 0
         self.ns.sent = 0
         self.ns.recv = 0
+        self.ns.handshake_failures = 0
+        self.ns.broker_errors = 0
+        self.ns.invalid_commands = 0
+        self.ns.malformed_commands = 0
+        self.ns.broker_requests = 0
+        self.ns.broker_latency = 0.0  # seconds, sum of all broker requests
         self.counter = 0
 
     def info(self) -> typing.Iterable[str]:
@@ -161,12 +172,12 @@ async def getServerStats(detailed: bool = False) -> None:
     context.verify_mode = ssl.CERT_NONE  # For ServerStats, does not checks certificate
 
     try:
-        host = cfg.listen_address if cfg.listen_address != '0.0.0.0' else 'localhost'
+        host = cfg.listen_address if cfg.listen_address not in ('0.0.0.0', '::', '*') else 'localhost'  # nosec
+        host = host[1:-1] if host.startswith('[') and host.endswith(']') else host
         reader: asyncio.StreamReader
         writer: asyncio.StreamWriter
 
-        with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as sock:
-            sock.connect((host, cfg.listen_port))
+        with socket.create_connection((host, cfg.listen_port)) as sock:
             # Send HANDSHAKE
             sock.sendall(consts.HANDSHAKE_V1)
             # Ugrade connection to TLS
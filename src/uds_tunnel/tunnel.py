@@ -29,13 +29,19 @@
 Author: Adolfo Gómez, dkmaster at dkmon dot com
 '''
 import asyncio
+import functools
+import inspect
+import ipaddress
+import json
 import typing
 import logging
 import socket
+import ssl
+import time
 
 import aiohttp
 
-from . import consts, config, stats, tunnel_client
+from . import bandwidth, consts, config, exceptions, flow, ratelimit, stats, tunnel_client
 
 
 logger = logging.getLogger(__name__)
@@ -45,7 +51,7 @@ if typing.TYPE_CHECKING:
 
 
 # Protocol
-class TunnelProtocol(asyncio.Protocol):
+class TunnelProtocol(flow.FlowControl, asyncio.Protocol):
     # owner Proxy class
     owner: 'proxy.Proxy'
 
@@ -62,6 +68,8 @@ class TunnelProtocol(asyncio.Protocol):
 
     # Ticket
     notify_ticket: bytes  # Only exists when we have created the client connection
+    # Service name, as provided by broker (optional, only for logging purposes)
+    service: str
 
     # source of connection
     source: typing.Tuple[str, int]
@@ -74,9 +82,27 @@ class TunnelProtocol(asyncio.Protocol):
 
     # Counters & stats related
     stats_manager: stats.StatsManager
+    # Bandwidth limit of this tunnel (global one, if any, is on owner)
+    bandwidth: typing.Optional[bandwidth.TokenBucket]
+
+    # Broker http session, shared by all tunnels of this process (and loop) to reuse connections
+    _session: typing.ClassVar[typing.Optional[aiohttp.ClientSession]] = None
+    _session_loop: typing.ClassVar[typing.Optional[asyncio.AbstractEventLoop]] = None
+    # Client certificate context for broker, if any (loaded once per process)
+    _ssl_context: typing.ClassVar[typing.Optional[ssl.SSLContext]] = None
+    # Broker responses, by ticket and source ip, with its expiration time (only if uds_ticket_cache_ttl)
+    _ticket_cache: typing.ClassVar[
+        typing.Dict[typing.Tuple[bytes, str], typing.Tuple[float, typing.Dict[str, typing.Any]]]
+    ] = {}
+    # Uds servers that failed, with the time until they are tried again first
+    _broker_down: typing.ClassVar[typing.Dict[str, float]] = {}
+    # Broker ticket requests in progress on this process
+    broker_pending: typing.ClassVar[int] = 0
 
     # If there is a timeout task running
     timeout_task: typing.Optional[asyncio.Task] = None
+    # Open task (broker request and connection to destination), if OPEN received
+    open_task: typing.Optional[asyncio.Task] = None
 
     def __init__(self, owner: 'proxy.Proxy') -> None:
         # If no other side is given, we are the server part
@@ -84,6 +110,7 @@ class TunnelProtocol(asyncio.Protocol):
         # transport is undefined until connection_made is called
         self.cmd = b''
         self.notify_ticket = b''
+        self.service = ''
         self.owner = owner
         self.source = ('', 0)
         self.destination = ('', 0)
@@ -94,6 +121,9 @@ class TunnelProtocol(asyncio.Protocol):
         # In this case, only do_proxy is used
         self.client = None
         self.stats_manager = stats.StatsManager(owner.ns)
+        self.bandwidth = (
+            bandwidth.TokenBucket(owner.cfg.tunnel_bandwidth_limit) if owner.cfg.tunnel_bandwidth_limit else None
+        )
         # We start processing command
         # After command, we can process stats or do_proxy, that is the "normal" operation
         self.runner = self.do_command
@@ -107,11 +137,25 @@ class TunnelProtocol(asyncio.Protocol):
         # Clean timeout now, we have received all data
         self.clean_timeout()
 
+        # Check we are not over the max connections limit (approximate, counters are shared by workers)
+        if self.owner.cfg.max_connections and self.owner.ns.current >= self.owner.cfg.max_connections:
+            logger.error('TOO MANY CONNECTIONS, REJECTED %s', self.pretty_source())
+            self.transport.write(consts.RESPONSE_BUSY)
+            self.close_connection()
+            return
+
+        # Shed load if broker is already busy with our requests, better than making clients wait for timeouts
+        if self.owner.cfg.uds_max_pending and TunnelProtocol.broker_pending >= self.owner.cfg.uds_max_pending:
+            logger.error('BROKER BUSY, REJECTED %s', self.pretty_source())
+            self.transport.write(consts.RESPONSE_BUSY)
+            self.close_connection()
+            return
+
         # Ticket received, now process it with UDS
         ticket = self.cmd[consts.COMMAND_LENGTH :]
 
         # Stop reading from this side until open is done
-        self.transport.pause_reading()
+        self.pause_reading('open')
 
         # clean up the command
         self.cmd = b''
@@ -119,48 +163,122 @@ class TunnelProtocol(asyncio.Protocol):
         loop = asyncio.get_running_loop()
 
         async def open_client() -> None:
+            started = time.monotonic()
+            # Counted only once running, so a task cancelled before starting does not leave it counted
+            TunnelProtocol.broker_pending += 1
             try:
                 result = await TunnelProtocol.get_ticket_from_uds(self.owner.cfg, ticket, self.source)
             except Exception as e:
-                logger.error('ERROR %s', e.args[0] if e.args else e)
+                if isinstance(e, exceptions.BrokerError):
+                    self.owner.ns.broker_errors += 1
+                # Malformed or unknown tickets are client failures, broker ones are not
+                if isinstance(e, (ValueError, exceptions.BrokerNotFoundError)):
+                    self.owner.add_failure(self.source[0])
+                logger.error('ERROR from %s: %s', self.pretty_source(), e.args[0] if e.args else e)
                 self.transport.write(consts.RESPONSE_ERROR_TICKET)
                 self.transport.close()  # And force close
                 return
+            finally:
+                TunnelProtocol.broker_pending -= 1
+                self.owner.ns.broker_requests += 1
+                self.owner.ns.broker_latency += time.monotonic() - started
+
+            # Client may have gone while waiting for broker, do not open a connection nobody will use
+            if self.transport.is_closing():
+                logger.info('CLIENT CLOSED BEFORE OPEN %s', self.pretty_source())
+                # But broker has already registered the start, so let it know it has finished
+                if result.get('notify'):
+                    self.owner.notify_task = loop.create_task(self.notify_end_with_timeout(result['notify'].encode()))
+                return
 
             # store for future use
             self.destination = (result['host'], int(result['port']))
-            self.notify_ticket = result['notify'].encode()
+            # Not present on cached responses, only the tunnel that got it from broker notifies its end
+            self.notify_ticket = result.get('notify', '').encode()
+            self.service = str(result.get('service') or '')
+
+            if self.owner.cfg.allowed_ports and self.destination[1] not in self.owner.cfg.allowed_ports:
+                logger.error(
+                    'DESTINATION PORT NOT ALLOWED FROM %s to %s',
+                    self.pretty_source(),
+                    self.pretty_destination(),
+                )
+                self.transport.write(consts.RESPONSE_FORBIDDEN)
+                self.close_connection()
+                return
+
+            # If required, destination must be an ip, so no dns resolution is done at all
+            if self.owner.cfg.require_ip_remotes and not TunnelProtocol.is_ip(self.destination[0]):
+                logger.error(
+                    'DESTINATION NOT AN IP FROM %s to %s',
+                    self.pretty_source(),
+                    self.pretty_destination(),
+                )
+                self.transport.write(consts.RESPONSE_FORBIDDEN)
+                self.close_connection()
+                return
 
             logger.info(
-                'OPEN TUNNEL FROM %s to %s',
+                'OPEN TUNNEL FROM %s to %s%s',
                 self.pretty_source(),
                 self.pretty_destination(),
+                self.pretty_service(),
             )
 
             try:
-                family = (
-                    socket.AF_INET6
-                    if ':' in self.destination[0] or (self.owner.cfg.ipv6 and '.' not in self.destination[0])
-                    else socket.AF_INET
-                )
-                (_, self.client) = await loop.create_connection(
-                    lambda: tunnel_client.TunnelClientProtocol(self),
-                    self.destination[0],
-                    self.destination[1],
-                    family=family,
+                options: typing.Dict[str, typing.Any] = {}
+                if (
+                    self.owner.cfg.happy_eyeballs_delay
+                    and not TunnelProtocol.is_ip(self.destination[0])
+                    and TunnelProtocol.supports_happy_eyeballs(type(loop))
+                ):
+                    # Race all addresses of destination (ipv4 and ipv6), so a dead one does not stall us
+                    options['happy_eyeballs_delay'] = self.owner.cfg.happy_eyeballs_delay
+                else:
+                    options['family'] = (
+                        socket.AF_INET6
+                        if ':' in self.destination[0] or (self.owner.cfg.ipv6 and '.' not in self.destination[0])
+                        else socket.AF_INET
+                    )
+                (_, self.client) = await asyncio.wait_for(
+                    loop.create_connection(
+                        lambda: tunnel_client.TunnelClientProtocol(self),
+                        self.destination[0],
+                        self.destination[1],
+                        **options,
+                    ),
+                    timeout=self.owner.cfg.connect_timeout or None,
                 )
 
+                # And also while connecting, so ensure destination connection is not orphaned
+                if self.transport.is_closing():
+                    logger.info('CLIENT CLOSED BEFORE OPEN %s', self.pretty_source())
+                    self.client.close_connection()
+                    return
+
                 # Resume reading
-                self.transport.resume_reading()
+                self.resume_reading('open')
                 # send OK to client
                 self.transport.write(b'OK')
+                # And now, let destination data flow to client
+                self.client.resume_reading('open')
                 self.stats_manager.increment_connections()  # Increment connections counters
+            except asyncio.TimeoutError:
+                logger.error(
+                    'CONNECTION TIMEOUT FROM %s to %s after %s seconds',
+                    self.pretty_source(),
+                    self.pretty_destination(),
+                    self.owner.cfg.connect_timeout,
+                )
+                self.close_connection()
             except Exception as e:
-                logger.error('CONNECTION FAILED: %s', e)
+                logger.error(
+                    'CONNECTION FAILED FROM %s to %s: %s', self.pretty_source(), self.pretty_destination(), e
+                )
                 self.close_connection()
 
         # add open other side to the loop
-        loop.create_task(open_client())
+        self.open_task = loop.create_task(open_client())
         # From now, proxy connection
         self.runner = self.do_proxy
 
@@ -201,6 +319,7 @@ class TunnelProtocol(asyncio.Protocol):
         try:
             await asyncio.sleep(wait)
             logger.error('TIMEOUT FROM %s', self.pretty_source())
+            self.owner.add_failure(self.source[0])
             try:
                 self.transport.write(consts.RESPONSE_ERROR_TIMEOUT)
             except Exception:  # nosec: Transport not available, ignore
@@ -227,6 +346,18 @@ class TunnelProtocol(asyncio.Protocol):
             self.timeout_task.cancel()
             self.timeout_task = None
 
+    @property
+    def peer(self) -> typing.Optional['tunnel_client.TunnelClientProtocol']:
+        return self.client
+
+    @property
+    def write_timeout(self) -> float:
+        return self.owner.cfg.write_timeout
+
+    def write_stalled(self) -> None:
+        logger.error('WRITE STALLED TO %s', self.pretty_source())
+        self.close_connection()
+
     def do_command(self, data: bytes) -> None:
         if self.cmd == b'':
             logger.info('CONNECT FROM %s (%s/%s)', self.pretty_source(), self.tls_version, self.tls_cipher)
@@ -236,6 +367,18 @@ class TunnelProtocol(asyncio.Protocol):
 
         if len(self.cmd) >= consts.COMMAND_LENGTH:
             command = self.cmd[: consts.COMMAND_LENGTH]
+            # Valid command, but with more data than it can have (nothing must be sent before our response)
+            if len(self.cmd) > consts.COMMAND_MAX_LENGTH.get(command, len(self.cmd)):
+                self.owner.ns.malformed_commands += 1
+                logger.error(
+                    'ERROR MALFORMED COMMAND %s from %s (%s bytes)',
+                    command.decode(),
+                    self.pretty_source(),
+                    len(self.cmd),
+                )
+                self.transport.write(consts.RESPONSE_ERROR_COMMAND)
+                self.close_connection()
+                return
             try:
                 if command == consts.COMMAND_OPEN:
                     self.process_open()
@@ -251,12 +394,15 @@ class TunnelProtocol(asyncio.Protocol):
                     try:
                         self.process_stats(full=command == consts.COMMAND_STAT)
                     except Exception as e:
-                        logger.error('ERROR processing stats: %s', e.args[0] if e.args else e)
+                        logger.error(
+                            'ERROR processing stats from %s: %s', self.pretty_source(), e.args[0] if e.args else e
+                        )
                     self.close_connection()
                     return
-                raise Exception('Invalid command')
-            except Exception:
-                logger.error('ERROR from %s', self.pretty_source())
+                self.owner.ns.invalid_commands += 1
+                raise Exception(f'Invalid command {command!r}')
+            except Exception as e:
+                logger.error('ERROR from %s: %s', self.pretty_source(), e)
                 self.transport.write(consts.RESPONSE_ERROR_COMMAND)
                 self.close_connection()
                 return
@@ -265,44 +411,73 @@ class TunnelProtocol(asyncio.Protocol):
 
     def do_proxy(self, data: bytes) -> None:
         self.stats_manager.as_sent_counter.add(len(data))
+        self.consume_bandwidth(self, len(data))
         # do_proxy will only be called if other_side is set to the other side of the tunnel, no None is possible
         typing.cast('tunnel_client.TunnelClientProtocol', self.client).send(data)
 
     def send(self, data: bytes) -> None:
         self.stats_manager.as_recv_counter.add(len(data))
+        if self.client:
+            self.consume_bandwidth(self.client, len(data))
         self.transport.write(data)
 
+    def consume_bandwidth(self, side: flow.FlowControl, size: int) -> None:
+        # Stop reading from side while over any of the bandwidth limits (tunnel or global)
+        delay = max(
+            (bucket.consume(size) for bucket in (self.bandwidth, self.owner.bandwidth) if bucket),
+            default=0.0,
+        )
+        if delay:
+            side.throttle(delay)
+
     def close_connection(self):
         try:
             self.clean_timeout()  # If a timeout is set, clean it
+            self.stop_write_watchdog()
             if not self.transport.is_closing():  # Attribute may alreade not be set
                 self.transport.close()
         except AttributeError:  # not initialized transport, fine...
             pass
         except Exception as e:   # nosec: best effort
-            logger.error('ERROR closing connection: %s', e)
+            logger.error('ERROR closing connection from %s: %s', self.pretty_source(), e)
 
     def notify_end(self):
         if self.notify_ticket:
             logger.info(
-                'TERMINATED %s to %s, s:%s, r:%s, t:%s',
+                'TERMINATED %s to %s%s, s:%s, r:%s, t:%s',
                 self.pretty_source(),
                 self.pretty_destination(),
+                self.pretty_service(),
                 self.stats_manager.sent,
                 self.stats_manager.recv,
                 int(self.stats_manager.current_time - self.stats_manager.start_time),
             )
-            # Notify end to uds, using a task becase we are not an async function
-            asyncio.get_event_loop().create_task(
-                TunnelProtocol.notify_end_to_uds(self.owner.cfg, self.notify_ticket, self.stats_manager)
+            # Connection is not running anymore, so it is not counted while broker is notified,
+            # but counters are final now, and they are the ones notified
+            self.stats_manager.close()
+            # Notify end to uds, using a task becase we are not an async function (owner waits for it)
+            self.owner.notify_task = asyncio.get_event_loop().create_task(
+                self.notify_end_with_timeout(self.notify_ticket)
             )
             self.notify_ticket = b''  # Clean up so no more notifications
         else:
             logger.info('TERMINATED %s', self.pretty_source())
+            self.stats_manager.close()
 
-        self.stats_manager.close()
         self.owner.finished.set()
 
+    async def notify_end_with_timeout(self, ticket: bytes) -> None:
+        try:
+            # A slow broker must not keep the tunnel waiting forever
+            await asyncio.wait_for(
+                TunnelProtocol.notify_end_to_uds(self.owner.cfg, ticket, self.stats_manager),
+                timeout=self.owner.cfg.uds_timeout or None,
+            )
+        except asyncio.TimeoutError:
+            logger.warning('Stop notification for %s timed out', self.pretty_source())
+        except Exception as e:
+            logger.error('ERROR notifying stop for %s: %s', self.pretty_source(), e)
+
     def connection_made(self, transport: 'asyncio.transports.BaseTransport') -> None:
         # We know for sure that the transport is a Transport.
 
@@ -310,6 +485,7 @@ class TunnelProtocol(asyncio.Protocol):
         self.set_timeout(self.owner.cfg.command_timeout)
 
         self.transport = typing.cast('asyncio.transports.Transport', transport)
+        TunnelProtocol.set_keepalive(self.owner.cfg, transport)
         # Get source
         self.source = self.transport.get_extra_info('peername')
         logger.debug('Connection made: %s', self.source)
@@ -323,9 +499,17 @@ class TunnelProtocol(asyncio.Protocol):
         self.cmd = b''
 
     def data_received(self, data: bytes):
-        self.runner(data)  # send data to current runner (command or proxy)
+        try:
+            self.runner(data)  # send data to current runner (command or proxy)
+        except Exception as e:
+            # Never leave a tunnel half working because of an unexpected error
+            logger.exception('UNEXPECTED ERROR on tunnel from %s: %s', self.pretty_source(), e)
+            self.close_connection()
 
     def connection_lost(self, exc: typing.Optional[Exception]) -> None:
+        # Closed by client before sending a command is not a timeout
+        self.clean_timeout()
+        self.stop_write_watchdog()
         # Ensure close other side if not server_side
         if self.client:
             self.client.close_connection()
@@ -341,37 +525,202 @@ class TunnelProtocol(asyncio.Protocol):
             return '[' + address[0] + ']:' + str(address[1])
         return address[0] + ':' + str(address[1])
 
+    @staticmethod
+    def set_keepalive(cfg: config.ConfigurationType, transport: 'asyncio.transports.BaseTransport') -> None:
+        # Dead peers (that will never send EOF) are detected by the kernel, closing the connection
+        if not cfg.keepalive_interval:
+            return
+        sock = transport.get_extra_info('socket')
+        if sock is None:
+            return
+        try:
+            sock.setsockopt(socket.SOL_SOCKET, socket.SO_KEEPALIVE, 1)
+            sock.setsockopt(socket.IPPROTO_TCP, socket.TCP_KEEPIDLE, cfg.keepalive_interval)
+            sock.setsockopt(socket.IPPROTO_TCP, socket.TCP_KEEPINTVL, cfg.keepalive_interval)
+            sock.setsockopt(
+                socket.IPPROTO_TCP, socket.TCP_KEEPCNT, max(1, -(-cfg.keepalive_timeout // cfg.keepalive_interval))
+            )
+        except (AttributeError, OSError) as e:  # Not all platforms have all options
+            logger.warning('Could not set keepalive: %s', e)
+
+    @staticmethod
+    @functools.lru_cache(maxsize=None)
+    def supports_happy_eyeballs(loop_type: type) -> bool:
+        # Not every event loop supports it (uvloop does not)
+        try:
+            return 'happy_eyeballs_delay' in inspect.signature(loop_type.create_connection).parameters
+        except (TypeError, ValueError):  # No signature available, so can't be used
+            return False
+
+    @staticmethod
+    def is_ip(host: str) -> bool:
+        try:
+            ipaddress.ip_address(host)
+            return True
+        except ValueError:
+            return False
+
     # source address, pretty format
     def pretty_source(self) -> str:
         return TunnelProtocol.pretty_address(self.source)
 
+    # destination address, as allowed to be logged by log_destination
     def pretty_destination(self) -> str:
+        if self.owner.cfg.log_destination == 'none':
+            return 'hidden'
+        if self.owner.cfg.log_destination == 'redacted':
+            return self.destination[0]
         return TunnelProtocol.pretty_address(self.destination)
 
+    # service name, if provided by broker, ready to be appended to log lines
+    def pretty_service(self) -> str:
+        return f' ({self.service})' if self.service else ''
+
+    @staticmethod
+    def get_session(cfg: config.ConfigurationType) -> aiohttp.ClientSession:
+        # Sessions are bound to the loop they were created on
+        loop = asyncio.get_running_loop()
+        session = TunnelProtocol._session
+        if session is not None and not session.closed and TunnelProtocol._session_loop is not loop:
+            # Can't be awaited from this loop, so just release its pooled connections (if its loop still works)
+            old_loop = TunnelProtocol._session_loop
+            try:
+                if old_loop is not None and not old_loop.is_closed() and session.connector is not None:
+                    session.connector.close()
+            except Exception as e:  # nosec: best effort
+                logger.debug('Closing stale broker session: %s', e)
+            session.detach()
+        if session is None or session.closed:
+            connector: typing.Optional[aiohttp.TCPConnector] = None
+            if cfg.uds_client_certificate and cfg.uds_cert_fingerprint:
+                # Requests use client certificate context as ssl, so pinned fingerprint is checked by connector
+                connector = aiohttp.TCPConnector(ssl=aiohttp.Fingerprint(bytes.fromhex(cfg.uds_cert_fingerprint)))
+            TunnelProtocol._session = aiohttp.ClientSession(
+                headers={'User-Agent': consts.USER_AGENT}, connector=connector
+            )
+            TunnelProtocol._session_loop = loop
+        return TunnelProtocol._session
+
+    @staticmethod
+    def broker_ssl_context(cfg: config.ConfigurationType) -> ssl.SSLContext:
+        # Used only if a client certificate must be presented to broker
+        if TunnelProtocol._ssl_context is None:
+            context = ssl.create_default_context()
+            # If pinned, fingerprint replaces certificate verification
+            if cfg.uds_verify_ssl is False or cfg.uds_cert_fingerprint:
+                context.check_hostname = False
+                context.verify_mode = ssl.CERT_NONE
+            context.load_cert_chain(certfile=cfg.uds_client_certificate, keyfile=cfg.uds_client_key)
+            TunnelProtocol._ssl_context = context
+        return TunnelProtocol._ssl_context
+
+    @staticmethod
+    async def close_session() -> None:
+        if TunnelProtocol._session is not None and TunnelProtocol._session_loop is asyncio.get_running_loop():
+            await TunnelProtocol._session.close()
+        TunnelProtocol._session = TunnelProtocol._session_loop = None
+
     @staticmethod
     async def _read_from_uds(
         cfg: config.ConfigurationType,
         ticket: bytes,
         msg: str,
         queryParams: typing.Optional[typing.Mapping[str, str]] = None,
+    ) -> typing.MutableMapping[str, typing.Any]:
+        if cfg.uds_backend == 'file':
+            return TunnelProtocol._read_from_file(cfg, ticket, msg)
+        # Servers marked as down are only used if all the others fail
+        now = time.monotonic()
+        servers = sorted(cfg.uds_servers, key=lambda server: TunnelProtocol._broker_down.get(server, 0) > now)
+        for server in servers:
+            try:
+                result = await TunnelProtocol._read_from_url(cfg, server, ticket, msg, queryParams)
+                TunnelProtocol._broker_down.pop(server, None)
+                return result
+            except exceptions.BrokerError as e:
+                if not e.retryable:  # Server answered, so no failover
+                    raise
+                TunnelProtocol._broker_down[server] = time.monotonic() + consts.BROKER_DOWN_TIME
+                if server == servers[-1]:
+                    raise
+                logger.warning('%s, trying next uds server', e)
+        raise exceptions.BrokerError('No uds server available')  # Not reachable, uds_servers is never empty
+
+    @staticmethod
+    async def _read_from_url(
+        cfg: config.ConfigurationType,
+        server: str,
+        ticket: bytes,
+        msg: str,
+        queryParams: typing.Optional[typing.Mapping[str, str]] = None,
     ) -> typing.MutableMapping[str, typing.Any]:
         try:
-            url = cfg.uds_server + '/' + ticket.decode() + '/' + msg + '/' + cfg.uds_token
+            url = server + '/' + ticket.decode() + '/' + msg + '/' + cfg.uds_token
             if queryParams:
                 url += '?' + '&'.join([f'{key}={value}' for key, value in queryParams.items()])
             # Set options
-            options: typing.Dict[str, typing.Any] = {'timeout': cfg.uds_timeout}
-            if cfg.uds_verify_ssl is False:
+            options: typing.Dict[str, typing.Any] = {'timeout': aiohttp.ClientTimeout(total=cfg.uds_timeout)}
+            if cfg.uds_client_certificate:
+                options['ssl'] = TunnelProtocol.broker_ssl_context(cfg)
+            elif cfg.uds_cert_fingerprint:
+                options['ssl'] = aiohttp.Fingerprint(bytes.fromhex(cfg.uds_cert_fingerprint))
+            elif cfg.uds_verify_ssl is False:
                 options['ssl'] = False
             # Requests url with aiohttp
 
-            async with aiohttp.ClientSession(headers={'User-Agent': consts.USER_AGENT}) as session:
-                async with session.get(url, **options) as r:
-                    if not r.ok:
-                        raise Exception(await r.text())
-                    return await r.json()
+            async with TunnelProtocol.get_session(cfg).get(url, **options) as r:
+                # Connection is kept on the session pool, idle until next request, so detect if broker is gone
+                if r.connection and r.connection.transport:
+                    TunnelProtocol.set_keepalive(cfg, r.connection.transport)
+                if not r.ok:
+                    # Error body is only for logging, so it is truncated to the limit instead of fully read
+                    error = (await r.content.read(cfg.uds_max_response_size)).decode(errors='replace')
+                    raise exceptions.broker_error_for_status(
+                        r.status, f'TICKET COMMS ERROR: {ticket.decode()} {msg} {r.status} {error}'
+                    )
+                # Read response with a size limit, so a misbehaving broker can't make us buffer anything
+                body = b''
+                while chunk := await r.content.read(consts.BUFFER_SIZE):
+                    body += chunk
+                    if len(body) > cfg.uds_max_response_size:
+                        raise exceptions.BrokerResponseTooLargeError(
+                            f'TICKET INVALID RESPONSE: {ticket.decode()} {msg} over {cfg.uds_max_response_size} bytes'
+                        )
+                try:
+                    return json.loads(body)
+                except ValueError as e:  # Invalid json (or encoding)
+                    raise exceptions.BrokerDecodeError(
+                        f'TICKET INVALID RESPONSE: {ticket.decode()} {msg} {e!s}'
+                    ) from e
+        except exceptions.BrokerError:
+            raise
+        except asyncio.TimeoutError as e:
+            raise exceptions.BrokerTimeoutError(
+                f'TICKET COMMS TIMEOUT: {ticket.decode()} {msg} after {cfg.uds_timeout} seconds'
+            ) from e
+        except aiohttp.ClientConnectionError as e:
+            # Network errors are retryable
+            raise exceptions.BrokerUnavailableError(
+                f'TICKET COMMS ERROR: {ticket.decode()} {msg} {e!s}', retryable=True
+            ) from e
         except Exception as e:
-            raise Exception(f'TICKET COMMS ERROR: {ticket.decode()} {msg} {e!s}') from e
+            raise exceptions.BrokerError(f'TICKET COMMS ERROR: {ticket.decode()} {msg} {e!s}') from e
+
+    @staticmethod
+    def _read_from_file(
+        cfg: config.ConfigurationType, ticket: bytes, msg: str
+    ) -> typing.MutableMapping[str, typing.Any]:
+        # Tickets file is read on every request, so it can be changed while running
+        if msg == 'stop':
+            return {}
+        try:
+            with open(cfg.uds_server, 'r', encoding='utf-8') as f:
+                tickets = json.load(f)
+        except (OSError, ValueError) as e:
+            raise exceptions.BrokerUnavailableError(f'TICKET COMMS ERROR: {ticket.decode()} {msg} {e!s}') from e
+        if not isinstance(tickets, dict) or not isinstance(tickets.get(ticket.decode()), dict):
+            raise exceptions.BrokerNotFoundError(f'TICKET COMMS ERROR: {ticket.decode()} {msg} not found')
+        return dict(tickets[ticket.decode()])
 
     @staticmethod
     async def get_ticket_from_uds(
@@ -381,7 +730,70 @@ class TunnelProtocol(asyncio.Protocol):
         if consts.TICKET_REGEX.match(ticket.decode(errors='replace')) is None:
             raise ValueError(f'TICKET INVALID ({ticket.decode(errors="replace")})')
 
-        return await TunnelProtocol._read_from_uds(cfg, ticket, address[0])
+        # Broker expects plain ipv4 for ipv4 clients, even if received on a dual stack socket (::ffff:a.b.c.d)
+        source_ip = ratelimit.canonical_ip(address[0])
+
+        cached = TunnelProtocol._ticket_cache.get((ticket, source_ip)) if cfg.uds_ticket_cache_ttl else None
+        if cached and cached[0] > time.monotonic():
+            logger.debug('TICKET %s from cache', ticket.decode())
+            return dict(cached[1])
+
+        # Retry transient failures, with exponential backoff, but never beyond uds_timeout
+        async def read_with_retries() -> typing.MutableMapping[str, typing.Any]:
+            delay = cfg.uds_retry_delay
+            started = time.monotonic()
+            retry = 0
+            while True:
+                try:
+                    return await TunnelProtocol._read_from_uds(cfg, ticket, source_ip)
+                except exceptions.BrokerError as e:
+                    if (
+                        not e.retryable
+                        or retry >= cfg.uds_retries
+                        or (cfg.uds_timeout and time.monotonic() - started + delay > cfg.uds_timeout)
+                    ):
+                        raise
+                    logger.warning('%s (from %s), retrying in %s seconds', e, source_ip, delay)
+                await asyncio.sleep(delay)
+                delay *= 2
+                retry += 1
+
+        # Every attempt (on every uds server) can take up to uds_timeout, so whole request is also bounded
+        try:
+            result = await asyncio.wait_for(read_with_retries(), timeout=cfg.uds_timeout or None)
+        except asyncio.TimeoutError:
+            raise exceptions.BrokerTimeoutError(
+                f'TICKET COMMS TIMEOUT: {ticket.decode()} after {cfg.uds_timeout} seconds'
+            ) from None
+
+        # Ensure broker provided all needed data, so we fail here with a clear message
+        missing = [key for key in ('host', 'port', 'notify') if not result.get(key)]
+        if missing:
+            raise exceptions.BrokerError(f'TICKET INVALID RESPONSE: {ticket.decode()} missing {", ".join(missing)}')
+        try:
+            result['port'] = int(result['port'])
+        except (TypeError, ValueError):
+            raise exceptions.BrokerError(
+                f'TICKET INVALID RESPONSE: {ticket.decode()} invalid port {result["port"]!r}'
+            ) from None
+        if cfg.uds_ticket_cache_ttl:
+            TunnelProtocol.cache_ticket((ticket, source_ip), result, cfg.uds_ticket_cache_ttl)
+        return result
+
+    @staticmethod
+    def cache_ticket(
+        key: typing.Tuple[bytes, str], result: typing.Mapping[str, typing.Any], ttl: float
+    ) -> None:
+        now = time.monotonic()
+        cache = TunnelProtocol._ticket_cache
+        # Remove expired entries, and oldest ones if still full (dicts keep insertion order)
+        for k in [k for k, (expires, _) in cache.items() if expires <= now]:
+            del cache[k]
+        cache.pop(key, None)
+        while len(cache) >= consts.TICKET_CACHE_MAX_ENTRIES:
+            del cache[next(iter(cache))]
+        # Notify ticket is for the tunnel opened with this response, never for the ones reusing it
+        cache[key] = (now + ttl, {k: v for k, v in result.items() if k != 'notify'})
 
     @staticmethod
     async def notify_end_to_uds(
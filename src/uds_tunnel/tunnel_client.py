@@ -32,6 +32,8 @@ import asyncio
 import typing
 import logging
 
+from . import flow
+
 logger = logging.getLogger(__name__)
 
 if typing.TYPE_CHECKING:
@@ -39,7 +41,7 @@ if typing.TYPE_CHECKING:
 
 
 # Protocol
-class TunnelClientProtocol(asyncio.Protocol):
+class TunnelClientProtocol(flow.FlowControl, asyncio.Protocol):
     # Transport and other side of tunnel
     transport: 'asyncio.transports.Transport'
     receiver: 'tunnel.TunnelProtocol'
@@ -56,12 +58,38 @@ class TunnelClientProtocol(asyncio.Protocol):
         self.destination = ('', 0)
 
     def data_received(self, data: bytes):
-        self.receiver.send(data)
+        try:
+            self.receiver.send(data)
+        except Exception as e:
+            logger.exception(
+                'UNEXPECTED ERROR on tunnel from %s to %s: %s',
+                self.receiver.pretty_source(),
+                self.receiver.pretty_destination(),
+                e,
+            )
+            self.receiver.close_connection()  # Closing receiver will also close us
 
     def connection_made(self, transport: 'asyncio.transports.BaseTransport') -> None:
         self.transport = typing.cast('asyncio.transports.Transport', transport)
+        self.receiver.set_keepalive(self.receiver.owner.cfg, transport)
+        # Server-speaks-first destinations (banners) must not reach the client before the OK response,
+        # so keep early data on the socket buffer until the tunnel is fully open
+        self.pause_reading('open')
+
+    @property
+    def peer(self) -> 'tunnel.TunnelProtocol':
+        return self.receiver
+
+    @property
+    def write_timeout(self) -> float:
+        return self.receiver.owner.cfg.write_timeout
+
+    def write_stalled(self) -> None:
+        logger.error('WRITE STALLED FROM %s TO %s', self.receiver.pretty_source(), self.receiver.pretty_destination())
+        self.receiver.close_connection()  # Closing receiver will also close us
 
     def connection_lost(self, exc: typing.Optional[Exception]) -> None:
+        self.stop_write_watchdog()
         # Ensure close other side
         try:
             self.receiver.close_connection()
@@ -72,6 +100,7 @@ class TunnelClientProtocol(asyncio.Protocol):
         self.transport.write(data)
 
     def close_connection(self):
+        self.stop_write_watchdog()
         try:
             if not self.transport.is_closing():
                 self.transport.close()
@@ -30,6 +30,7 @@
 Author: Adolfo Gómez, dkmaster at dkmon dot com
 '''
 import os
+import ipaddress
 import pwd
 import sys
 import asyncio
@@ -37,6 +38,7 @@ import argparse
 import signal
 import ssl
 import socket
+import time
 import threading  # event for stop notification
 import typing
 import logging
@@ -57,7 +59,7 @@ except ImportError:
     setproctitle = None  # type: ignore
 
 
-from uds_tunnel import config, proxy, consts, processes, stats
+from uds_tunnel import config, proxy, consts, processes, stats, ratelimit, metrics, tunnel, bandwidth, log
 
 if typing.TYPE_CHECKING:
     from multiprocessing.connection import Connection
@@ -83,33 +85,56 @@ def setup_log(cfg: config.ConfigurationType) -> None:
             maxBytes=cfg.logsize,
             backupCount=cfg.lognumber,
         )
-        formatter = logging.Formatter(consts.LOGFORMAT)
+        formatter = log.JSONFormatter() if cfg.logformat == 'json' else logging.Formatter(consts.LOGFORMAT)
         fileh.setFormatter(formatter)
-        log = logging.getLogger()
-        log.setLevel(cfg.loglevel)
-        # for hdlr in log.handlers[:]:
-        #     log.removeHandler(hdlr)
-        log.addHandler(fileh)
+        root_logger = logging.getLogger()
+        root_logger.setLevel(cfg.loglevel)
+        # for hdlr in root_logger.handlers[:]:
+        #     root_logger.removeHandler(hdlr)
+        root_logger.addHandler(fileh)
     else:
         # Setup basic logging
-        log = logging.getLogger()
-        log.setLevel(cfg.loglevel)
+        root_logger = logging.getLogger()
+        root_logger.setLevel(cfg.loglevel)
         handler = logging.StreamHandler(sys.stderr)
         handler.setLevel(cfg.loglevel)
-        formatter = logging.Formatter('%(levelname)s - %(message)s')  # Basic log format, nice for syslog
+        formatter = (
+            log.JSONFormatter()
+            if cfg.logformat == 'json'
+            else logging.Formatter('%(levelname)s - %(message)s')  # Basic log format, nice for syslog
+        )
         handler.setFormatter(formatter)
-        log.addHandler(handler)
+        root_logger.addHandler(handler)
 
     # If debug, print config
     if cfg.loglevel.lower() == 'debug':
         logger.debug('Configuration: %s', cfg)
 
 
-async def tunnel_proc_async(pipe: 'Connection', cfg: config.ConfigurationType, ns: 'Namespace') -> None:
+async def tunnel_proc_async(
+    pipe: 'Connection',
+    cfg: config.ConfigurationType,
+    ns: 'Namespace',
+    bucket: typing.Optional[bandwidth.TokenBucket] = None,
+) -> None:
     loop = asyncio.get_running_loop()
 
     tasks: typing.List[asyncio.Task] = []
 
+    if cfg.happy_eyeballs_delay and not tunnel.TunnelProtocol.supports_happy_eyeballs(type(loop)):
+        logger.warning('happy_eyeballs_delay is not supported by %s event loop, ignoring it', type(loop).__module__)
+
+    # Global bandwidth limit is shared by all worker processes, if not provided we are the only one
+    if bucket is None and cfg.bandwidth_limit:
+        bucket = bandwidth.TokenBucket(cfg.bandwidth_limit)
+
+    def report_failure(ip: str) -> None:
+        # Handshake limiter is on main process, so failed sources are sent back through our pipe
+        try:
+            pipe.send(ip)
+        except Exception as e:
+            logger.debug('Could not report failure from %s: %s', ip, e)
+
     def add_autoremovable_task(task: asyncio.Task) -> None:
         tasks.append(task)
 
@@ -121,11 +146,15 @@ async def tunnel_proc_async(pipe: 'Connection', cfg: config.ConfigurationType, n
 
     def get_socket() -> typing.Tuple[typing.Optional[socket.socket], typing.Optional[typing.Tuple[str, int]]]:
         try:
-            while True:
+            # Check for stop from time to time, so we stop accepting connections as soon as possible
+            while not do_stop.is_set():
+                if not pipe.poll(1):
+                    continue
                 # Clear back event, for next data
                 msg: typing.Optional[typing.Tuple[socket.socket, typing.Tuple[str, int]]] = pipe.recv()
                 if msg:
                     return msg
+            return None, None
         except EOFError:
             logger.debug('Parent process closed connection')
             pipe.close()
@@ -181,7 +210,12 @@ async def tunnel_proc_async(pipe: 'Connection', cfg: config.ConfigurationType, n
                     logger.debug('CONNECTION from %s (pid: %s)', address, os.getpid())
                     # Due to proxy contains an "event" to stop, we need to create a new one for each connection
                     add_autoremovable_task(
-                        asyncio.create_task(proxy.Proxy(cfg, ns)(sock, context), name=f'proxy-{address}')
+                        asyncio.create_task(
+                            proxy.Proxy(cfg, ns, bucket, report_failure if cfg.handshake_max_failures else None)(
+                                sock, context
+                            ),
+                            name=f'proxy-{address}',
+                        )
                     )
                 except asyncio.CancelledError:  # pylint: disable=try-except-raise
                     raise  # Stop, but avoid generic exception
@@ -209,6 +243,14 @@ async def tunnel_proc_async(pipe: 'Connection', cfg: config.ConfigurationType, n
 
     logger.debug('Out of loop, stopping tasks: %s, running: %s', tasks, do_stop.is_set())
 
+    # Server stops accepting connections on stop, give running tunnels some time to finish
+    if cfg.shutdown_grace and tasks:
+        logger.info('Waiting up to %s seconds for %s tunnels to finish', cfg.shutdown_grace, len(tasks))
+        try:
+            await asyncio.wait(tasks[:], return_when=asyncio.ALL_COMPLETED, timeout=cfg.shutdown_grace)
+        except asyncio.CancelledError:
+            logger.info('Shutdown grace cancelled')
+
     # If any task is still running, cancel it
     for task in tasks:
         try:
@@ -220,38 +262,158 @@ async def tunnel_proc_async(pipe: 'Connection', cfg: config.ConfigurationType, n
     #    task.cancel()
 
     # Wait for all tasks to finish
-    await asyncio.wait(tasks, return_when=asyncio.ALL_COMPLETED)
+    if tasks:
+        try:
+            await asyncio.wait(tasks, return_when=asyncio.ALL_COMPLETED)
+        except asyncio.CancelledError:
+            logger.info('Waiting for tunnels to close cancelled')
+
+    # No more broker requests from this process
+    await tunnel.TunnelProtocol.close_session()
 
     logger.info('PROCESS %s stopped', os.getpid())
 
 
-def process_connection(client: socket.socket, addr: typing.Tuple[str, str], conn: 'Connection') -> None:
+def process_connection(
+    client: socket.socket,
+    addr: typing.Tuple[str, str],
+    conn: 'Connection',
+    limiter: typing.Optional[ratelimit.HandshakeLimiter] = None,
+    ns: typing.Optional['Namespace'] = None,
+) -> None:
+    def failed() -> None:
+        if limiter:
+            limiter.add_failure(addr[0])
+        if ns:
+            ns.handshake_failures += 1
+
     data: bytes = b''
+    # Whole handshake must arrive in time, so clients sending it byte by byte can't hold this thread longer
+    timeout = client.gettimeout()
+    deadline = time.monotonic() + timeout if timeout else None
     try:
         # First, ensure handshake (simple handshake) and command
-        data = client.recv(len(consts.HANDSHAKE_V1))
+        # Handshake may arrive split, so read exactly its length (never more, rest belongs to TLS)
+        while len(data) < len(consts.HANDSHAKE_V1):
+            if deadline is not None:
+                remaining = deadline - time.monotonic()
+                if remaining <= 0:
+                    raise socket.timeout('timed out')
+                client.settimeout(remaining)
+            chunk = client.recv(len(consts.HANDSHAKE_V1) - len(data))
+            if not chunk:
+                break  # Closed by peer
+            data += chunk
+            if not consts.HANDSHAKE_V1.startswith(data):
+                break  # Invalid data, no need to wait for more
+    except Exception as e:
+        # Handshake started but not finished in time is a failure, but not if nothing was sent (health checks)
+        if data and isinstance(e, socket.timeout):
+            logger.error('HANDSHAKE timeout from %s after %s bytes', addr[0], len(data))
+            failed()
+        else:
+            # Timeouts, resets... (slow clients, health checks), that are not invalid handshakes
+            logger.info('HANDSHAKE not completed from %s: %s', addr[0], e)
+        client.close()
+        return
+
+    if data != consts.HANDSHAKE_V1:
+        # Only wrong data counts as failure, not connections closed before sending it all (as tcp health checks)
+        if consts.HANDSHAKE_V1.startswith(data):
+            logger.info('HANDSHAKE not completed from %s: closed after %s bytes', addr[0], len(data))
+        else:
+            logger.error('HANDSHAKE invalid from %s: %s', addr[0], data.hex())
+            failed()
+        client.close()
+        return
 
-        if data != consts.HANDSHAKE_V1:
-            raise Exception(f'Invalid data from {addr[0]}: {data.hex()}')  # Invalid handshake
+    try:
         conn.send((client, addr))
         del client  # Ensure socket is controlled on child process
     except Exception as e:
-        logger.error('HANDSHAKE invalid from %s: %s', addr[0], e)
-        # Close Source and continue
+        logger.error('ERROR passing connection from %s to worker: %s', addr[0], e)
         client.close()
 
 
+def bind_listen_socket(
+    sock: socket.socket, address: typing.Tuple[str, int], retries: int, delay: float = consts.BIND_RETRY_DELAY
+) -> None:
+    """Binds the listen socket, retrying up to "retries" times if address is not available
+
+    Raises:
+        OSError: If the socket could not be bound after all retries
+    """
+    for retry in range(retries + 1):
+        try:
+            sock.bind(address)
+            return
+        except OSError as e:
+            if retry == retries:
+                raise
+            logger.warning('BIND to %s:%s failed (%s), retrying in %s seconds', address[0], address[1], e, delay)
+            time.sleep(delay)
+
+
+def listen_sockaddr(
+    address: str, port: int, ipv6: bool = False
+) -> typing.Tuple[socket.AddressFamily, typing.Tuple[str, int], bool]:
+    """Gets the family, socket address and if dual stack is wanted for a listen address
+
+    "*" means any address, ipv6 and ipv4 (as mapped addresses) if available.
+    IPv6 literals can be enclosed on brackets ([::1]). If ipv6 is forced, 0.0.0.0 means "::".
+    Hostnames are resolved (to their ipv6 address if ipv6 is forced and it has one).
+
+    Raises:
+        ValueError: If address is not "*", an ip address nor a resolvable hostname
+    """
+    if address == '*':
+        return socket.AF_INET6, ('::', port), True
+    host = address[1:-1] if address.startswith('[') and address.endswith(']') else address
+    try:
+        ip = ipaddress.ip_address(host)
+    except ValueError:
+        # Malformed ip addresses are not hostnames
+        if not host or ':' in host or '[' in host or host.replace('.', '').isdigit():
+            raise ValueError(f'Invalid listen address: {address!r}') from None
+        try:
+            addrs = socket.getaddrinfo(host, port, type=socket.SOCK_STREAM)
+        except OSError as e:
+            raise ValueError(f'Invalid listen address: {address!r} ({e})') from None
+        addrs.sort(key=lambda a: a[0] != (socket.AF_INET6 if ipv6 else socket.AF_INET))
+        ip = ipaddress.ip_address(addrs[0][4][0])
+    if ip.version == 4 and ipv6 and ip.is_unspecified:
+        return socket.AF_INET6, ('::', port), False
+    return (socket.AF_INET6 if ip.version == 6 else socket.AF_INET), (str(ip), port), False
+
+
 def tunnel_main(args: 'argparse.Namespace') -> None:
     cfg = config.read(args.config)
 
+    # Fail early if broker client certificate is not usable
+    broker_context: typing.Optional[ssl.SSLContext] = None
+    if cfg.uds_client_certificate:
+        try:
+            broker_context = tunnel.TunnelProtocol.broker_ssl_context(cfg)
+        except (OSError, ssl.SSLError) as e:
+            sys.stderr.write(f'Could not load uds client certificate {cfg.uds_client_certificate}: {e}\n')
+            sys.exit(consts.EXIT_CONFIG_ERROR)
+
     # Try to bind to port as running user
     # Wait for socket incoming connections and spread them
     socket.setdefaulttimeout(3.0)  # So we can check for stop from time to time and not block forever
-    sock = socket.socket(
-        socket.AF_INET6 if args.ipv6 or cfg.ipv6 or ':' in cfg.listen_address else socket.AF_INET,
-        socket.SOCK_STREAM,
-    )
+    try:
+        family, address, dual_stack = listen_sockaddr(cfg.listen_address, cfg.listen_port, args.ipv6 or cfg.ipv6)
+    except ValueError as e:
+        sys.stderr.write(f'Tunnel could not listen on {cfg.listen_address}:{cfg.listen_port}: {e}\n')
+        sys.exit(consts.EXIT_BIND_ERROR)
+
+    sock = socket.socket(family, socket.SOCK_STREAM)
     sock.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, True)
+    if dual_stack:
+        try:
+            sock.setsockopt(socket.IPPROTO_IPV6, socket.IPV6_V6ONLY, 0)
+        except (AttributeError, OSError) as e:
+            logger.warning('Dual stack not available, listening only on ipv6: %s', e)
     sock.setsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY, 1)
     # We will not reuse port, we only want a UDS tunnel server running on a port
     # but this may change on future...
@@ -260,7 +422,14 @@ def tunnel_main(args: 'argparse.Namespace') -> None:
     # except (AttributeError, OSError) as e:
     #     logger.warning('socket.REUSEPORT not available')
     try:
-        sock.bind((cfg.listen_address, cfg.listen_port))
+        bind_listen_socket(sock, address, cfg.bind_retries)
+    except OSError as e:
+        sys.stderr.write(f'Tunnel could not listen on {cfg.listen_address}:{cfg.listen_port}: {e}\n')
+        logger.error('MAIN: could not bind to %s:%s: %s', cfg.listen_address, cfg.listen_port, e)
+        sock.close()
+        sys.exit(consts.EXIT_BIND_ERROR)
+
+    try:
         sock.listen(consts.BACKLOG)
 
         # If running as root, and requested drop privileges after port bind
@@ -297,22 +466,60 @@ def tunnel_main(args: 'argparse.Namespace') -> None:
 
     stats_collector = stats.GlobalStats()
 
-    prcs = processes.Processes(tunnel_proc_async, cfg, stats_collector.ns)
+    # Global bandwidth limit, shared by tunnels of all workers
+    bucket = bandwidth.SharedTokenBucket(cfg.bandwidth_limit) if cfg.bandwidth_limit else None
+
+    prcs = processes.Processes(tunnel_proc_async, cfg, stats_collector.ns, bucket)
+
+    limiter = (
+        ratelimit.HandshakeLimiter(
+            cfg.handshake_max_failures, cfg.handshake_failures_window, cfg.handshake_ban_time
+        )
+        if cfg.handshake_max_failures
+        else None
+    )
+
+    metrics_server: typing.Optional[metrics.MetricsServer] = None
+    if cfg.metrics_address:
+        try:
+            metrics_server = metrics.MetricsServer(
+                (cfg.metrics_address, cfg.metrics_port),
+                stats_collector.ns,
+                # Not ready while stopping or if tickets can't be resolved
+                lambda: not do_stop.is_set() and metrics.broker_ready(cfg, broker_context),
+            )
+            metrics_server.start()
+            logger.info('Serving metrics on %s:%s', cfg.metrics_address, cfg.metrics_port)
+        except OSError as e:
+            # Metrics are not essential, so keep running without them
+            logger.error('MAIN: could not start metrics on %s:%s: %s', cfg.metrics_address, cfg.metrics_port, e)
 
     with ThreadPoolExecutor(max_workers=16) as executor:
         try:
             while not do_stop.is_set():
+                # Failures found by workers (wrong tickets, command timeouts) also count
+                if limiter:
+                    for ip in prcs.failed_sources():
+                        limiter.add_failure(ip)
                 try:
                     client, addr = sock.accept()
                     # logger.info('CONNECTION from %s', addr)
 
+                    # Refuse banned sources without reading anything from them
+                    if limiter and limiter.is_banned(addr[0]):
+                        logger.debug('REFUSED banned source %s', addr[0])
+                        client.close()
+                        continue
+
                     # Check if we have reached the max number of connections
                     # First part is checked on a thread, if HANDSHAKE is valid
                     # we will send socket to process pool
                     # Note: We use a thread pool here because we want to
                     #       ensure no denial of service is possible, or at least
                     #       we try to limit it (if connection delays too long, we will close it on the thread)
-                    executor.submit(process_connection, client, addr, prcs.best_child())
+                    executor.submit(
+                        process_connection, client, addr, prcs.best_child(), limiter, stats_collector.ns
+                    )
                 except socket.timeout:
                     pass  # Continue and retry
                 except Exception as e:
@@ -324,7 +531,10 @@ def tunnel_main(args: 'argparse.Namespace') -> None:
     if sock:
         sock.close()
 
-    prcs.stop()
+    prcs.stop(cfg.shutdown_grace)
+
+    if metrics_server:
+        metrics_server.stop()
 
     try:
         if cfg.pidfile:
@@ -0,0 +1,60 @@
+# -*- coding: utf-8 -*-
+#
+# Copyright (c) 2022 Virtual Cable S.L.U.
+# All rights reserved.
+#
+# Redistribution and use in source and binary forms, with or without modification,
+# are permitted provided that the following conditions are met:
+#
+#    * Redistributions of source code must retain the above copyright notice,
+#      this list of conditions and the following disclaimer.
+#    * Redistributions in binary form must reproduce the above copyright notice,
+#      this list of conditions and the following disclaimer in the documentation
+#      and/or other materials provided with the distribution.
+#    * Neither the name of Virtual Cable S.L. nor the names of its contributors
+#      may be used to endorse or promote products derived from this software
+#      without specific prior written permission.
+#
+# THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+# AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+# IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+# DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+# FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+# DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+# SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+# CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+# OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+# OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+'''
+Author: Adolfo Gómez, dkmaster at dkmon dot com
+'''
+import multiprocessing
+from unittest import TestCase, mock
+
+from uds_tunnel import bandwidth
+
+
+class TestTokenBucket(TestCase):
+    def test_token_bucket(self) -> None:
+        with mock.patch('uds_tunnel.bandwidth.time.monotonic') as monotonic:
+            monotonic.return_value = 1000.0
+            bucket = bandwidth.TokenBucket(1000)
+            # Burst of one second is allowed
+            self.assertEqual(bucket.consume(1000), 0)
+            # Over limit, must wait until debt is paid
+            self.assertAlmostEqual(bucket.consume(500), 0.5)
+            # After some time, tokens are refilled
+            monotonic.return_value = 1001.0
+            self.assertEqual(bucket.consume(400), 0)
+            # But never over capacity
+            monotonic.return_value = 1100.0
+            self.assertEqual(bucket.consume(1000), 0)
+            self.assertAlmostEqual(bucket.consume(100), 0.1)
+
+    def test_shared_token_bucket(self) -> None:
+        bucket = bandwidth.SharedTokenBucket(1000)
+        # Consumed by another process (as workers do), but it is the same bucket
+        process = multiprocessing.Process(target=bucket.consume, args=(1000,))
+        process.start()
+        process.join()
+        self.assertGreater(bucket.consume(500), 0.4)
@@ -29,6 +29,7 @@
 Author: Adolfo Gómez, dkmaster at dkmon dot com
 '''
 import hashlib
+import random
 
 from unittest import TestCase
 
@@ -39,13 +40,16 @@ class TestConfigFile(TestCase):
     def test_config_file(self) -> None:
         # Test in-memory configuration files ramdomly created
         for _ in range(100):
-            values, cfg = fixtures.get_config()
+            values, cfg = fixtures.get_config(
+                allowed_ports=','.join(str(random.randint(1, 65535)) for _ in range(random.randint(0, 4)))
+            )
 
             h = hashlib.sha256()
             h.update(values.get('secret', '').encode())
             # Adapt some values to config
             values['secret'] = h.hexdigest()
             values['allow'] = {values['allow']}  # convert to set
+            values['allowed_ports'] = {int(p) for p in values['allowed_ports'].split(',') if p}
             values['logsize'] = values['logsize'] * 1024 * 1024
             values['listen_address'] = values['address']
             values['listen_port'] = values['port']
@@ -55,3 +59,51 @@ class TestConfigFile(TestCase):
             # Ensure data is correct
             for k, v in values.items():
                 self.assertEqual(getattr(cfg, k), v, f'Error in {k}')
+
+    def test_config_uds_cert_fingerprint(self) -> None:
+        fingerprint = hashlib.sha256(b'certificate').hexdigest()
+        # Colons and case are ignored
+        pretty = ':'.join(fingerprint[i : i + 2] for i in range(0, len(fingerprint), 2)).upper()
+        _, cfg = fixtures.get_config(uds_cert_fingerprint=pretty)
+        self.assertEqual(cfg.uds_cert_fingerprint, fingerprint)
+
+        # Only SHA-256 fingerprints are valid
+        for invalid in (fingerprint[:40], 'not hex'):
+            with self.assertRaises(Exception):
+                fixtures.get_config(uds_cert_fingerprint=invalid)
+
+    def test_config_uds_client_certificate(self) -> None:
+        _, cfg = fixtures.get_config(uds_client_certificate='/tmp/client.pem', uds_client_key='/tmp/client.key')
+        self.assertEqual((cfg.uds_client_certificate, cfg.uds_client_key), ('/tmp/client.pem', '/tmp/client.key'))
+
+        # Can be used along with pinning
+        _, cfg = fixtures.get_config(
+            uds_client_certificate='/tmp/client.pem', uds_client_key='/tmp/client.key', uds_cert_fingerprint='00' * 32
+        )
+        self.assertEqual(cfg.uds_cert_fingerprint, '00' * 32)
+
+        # Both or none must be set
+        for invalid in (
+            {'uds_client_certificate': '/tmp/client.pem'},
+            {'uds_client_key': '/tmp/client.key'},
+        ):
+            with self.assertRaises(Exception):
+                fixtures.get_config(**invalid)
+
+    def test_config_allowed_ports(self) -> None:
+        _, cfg = fixtures.get_config(allowed_ports='1, 3389,65535')
+        self.assertEqual(cfg.allowed_ports, {1, 3389, 65535})
+
+        # Only valid tcp ports
+        for invalid in ('0,3389', '3389,70000', '-22', 'ssh'):
+            with self.assertRaises(Exception):
+                fixtures.get_config(allowed_ports=invalid)
+
+    def test_config_uds_backend(self) -> None:
+        # File backend uses uds_server as is (a path)
+        _, cfg = fixtures.get_config(uds_backend='file', uds_server='/tmp/tickets.json')
+        self.assertEqual((cfg.uds_backend, cfg.uds_server), ('file', '/tmp/tickets.json'))
+
+        for invalid in ({'uds_backend': 'ldap'}, {'uds_server': '/tmp/tickets.json'}):
+            with self.assertRaises(Exception):
+                fixtures.get_config(**invalid)
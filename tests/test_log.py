@@ -0,0 +1,80 @@
+# -*- coding: utf-8 -*-
+#
+# Copyright (c) 2022 Virtual Cable S.L.U.
+# All rights reserved.
+#
+# Redistribution and use in source and binary forms, with or without modification,
+# are permitted provided that the following conditions are met:
+#
+#    * Redistributions of source code must retain the above copyright notice,
+#      this list of conditions and the following disclaimer.
+#    * Redistributions in binary form must reproduce the above copyright notice,
+#      this list of conditions and the following disclaimer in the documentation
+#      and/or other materials provided with the distribution.
+#    * Neither the name of Virtual Cable S.L. nor the names of its contributors
+#      may be used to endorse or promote products derived from this software
+#      without specific prior written permission.
+#
+# THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+# AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+# IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+# DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+# FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+# DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+# SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+# CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+# OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+# OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+'''
+Author: Adolfo Gómez, dkmaster at dkmon dot com
+'''
+import io
+import json
+import asyncio
+import logging
+from unittest import IsolatedAsyncioTestCase
+
+from uds_tunnel import log
+
+
+class TestJSONLog(IsolatedAsyncioTestCase):
+    async def test_json_formatter(self) -> None:
+        output = io.StringIO()
+        handler = logging.StreamHandler(output)
+        handler.setFormatter(log.JSONFormatter())
+        logger = logging.getLogger('test_json_formatter')
+        logger.propagate = False
+        logger.setLevel(logging.DEBUG)
+        logger.addHandler(handler)
+
+        async def tunnel_task(ip: str) -> None:
+            # As done by proxy for every tunnel
+            log.source_ip.set(ip)
+            logger.info('OPEN TUNNEL FROM %s', ip)
+
+        try:
+            logger.warning('No tunnel')
+            await asyncio.gather(tunnel_task('10.0.0.1'), tunnel_task('::1'))
+            try:
+                raise ValueError('Failure')
+            except ValueError:
+                logger.exception('With exception')
+        finally:
+            logger.removeHandler(handler)
+
+        lines = [json.loads(line) for line in output.getvalue().splitlines()]
+        self.assertEqual(len(lines), 4)
+        for line in lines:
+            for key in ('timestamp', 'level', 'target', 'message'):
+                self.assertIn(key, line)
+            self.assertEqual(line['target'], 'test_json_formatter')
+
+        self.assertEqual(lines[0]['level'], 'WARNING')
+        self.assertNotIn('src_ip', lines[0])
+        # Each tunnel logs its own source ip
+        self.assertEqual(lines[1]['src_ip'], '10.0.0.1')
+        self.assertEqual(lines[1]['message'], 'OPEN TUNNEL FROM 10.0.0.1')
+        self.assertEqual(lines[2]['src_ip'], '::1')
+        # Source ip is not leaked outside the tunnel task
+        self.assertNotIn('src_ip', lines[3])
+        self.assertIn('ValueError: Failure', lines[3]['exception'])
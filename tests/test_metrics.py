@@ -0,0 +1,173 @@
+# -*- coding: utf-8 -*-
+#
+# Copyright (c) 2022 Virtual Cable S.L.U.
+# All rights reserved.
+#
+# Redistribution and use in source and binary forms, with or without modification,
+# are permitted provided that the following conditions are met:
+#
+#    * Redistributions of source code must retain the above copyright notice,
+#      this list of conditions and the following disclaimer.
+#    * Redistributions in binary form must reproduce the above copyright notice,
+#      this list of conditions and the following disclaimer in the documentation
+#      and/or other materials provided with the distribution.
+#    * Neither the name of Virtual Cable S.L. nor the names of its contributors
+#      may be used to endorse or promote products derived from this software
+#      without specific prior written permission.
+#
+# THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+# AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+# IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+# DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+# FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+# DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+# SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+# CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+# OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+# OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+'''
+Author: Adolfo Gómez, dkmaster at dkmon dot com
+'''
+import asyncio
+import hashlib
+import logging
+import os
+import ssl
+import tempfile
+import urllib.request
+import urllib.error
+from unittest import IsolatedAsyncioTestCase, mock
+
+from uds_tunnel import consts, metrics, stats
+
+from .utils import tuntools, tools, certs, fixtures
+
+logger = logging.getLogger(__name__)
+
+
+def scrape(port: int, path: str = '/metrics') -> str:
+    with urllib.request.urlopen(f'http://127.0.0.1:{port}{path}', timeout=4) as r:  # nosec: test url
+        return r.read().decode()
+
+
+class TestMetrics(IsolatedAsyncioTestCase):
+    async def asyncSetUp(self) -> None:
+        # Disable logging os slow tests
+        logging.disable(logging.WARNING)
+        return await super().asyncSetUp()
+
+    async def test_metrics(self) -> None:
+        loop = asyncio.get_running_loop()
+        stats_collector = stats.GlobalStats()
+        metrics_port = tools.get_free_port(False)
+        server = metrics.MetricsServer(('127.0.0.1', metrics_port), stats_collector.ns)
+        server.start()
+        try:
+            async with tools.AsyncTCPServer(host='127.0.0.1', port=5447) as remote:
+                async with tuntools.create_tunnel_proc(
+                    '127.0.0.1',
+                    7781,
+                    remote.host,
+                    remote.port,
+                    global_stats=stats_collector,
+                ) as (cfg, _):
+                    async with tuntools.open_tunnel_client(cfg) as (creader, cwriter):
+                        cwriter.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                        await cwriter.drain()
+                        self.assertEqual(await creader.read(1024), consts.RESPONSE_OK)
+
+                        data = await loop.run_in_executor(None, scrape, metrics_port)
+                        self.assertIn('# TYPE uds_tunnel_active_sessions gauge', data)
+                        self.assertIn('uds_tunnel_active_sessions 1\n', data)
+                        self.assertIn('uds_tunnel_sessions_total 1\n', data)
+                        self.assertIn('uds_tunnel_broker_latency_seconds_count 1\n', data)
+                        self.assertIn('uds_tunnel_handshake_failures_total 0\n', data)
+                        self.assertIn('uds_tunnel_bytes_sent_total', data)
+
+            # Only /metrics is served
+            with self.assertRaises(urllib.error.HTTPError):
+                await loop.run_in_executor(None, scrape, metrics_port, '/other')
+        finally:
+            server.stop()
+
+    async def test_health_and_ready(self) -> None:
+        loop = asyncio.get_running_loop()
+        stats_collector = stats.GlobalStats()
+        metrics_port = tools.get_free_port(False)
+        broker_port = tools.get_free_port(False)
+        broker_url = f'http://127.0.0.1:{broker_port}/uds/rest'
+        stopping = False
+
+        server = metrics.MetricsServer(
+            ('127.0.0.1', metrics_port),
+            stats_collector.ns,
+            lambda: not stopping and metrics.broker_reachable(broker_url, True, 1),
+        )
+        server.start()
+        try:
+            # Broker is not running, so alive but not ready
+            self.assertEqual(await loop.run_in_executor(None, scrape, metrics_port, '/healthz'), 'OK\n')
+            with self.assertRaises(urllib.error.HTTPError) as ctx:
+                await loop.run_in_executor(None, scrape, metrics_port, '/ready')
+            self.assertEqual(ctx.exception.code, 503)
+
+            async with tuntools.create_fake_broker_server('127.0.0.1', broker_port, response={}) as requests:
+                self.assertEqual(await loop.run_in_executor(None, scrape, metrics_port, '/ready'), 'READY\n')
+                # No ticket is needed for readiness check
+                self.assertIn(b'GET /uds/rest ', await requests.get())
+
+                # Stopping, not ready anymore
+                stopping = True
+                with self.assertRaises(urllib.error.HTTPError):
+                    await loop.run_in_executor(None, scrape, metrics_port, '/ready')
+        finally:
+            server.stop()
+
+    async def test_broker_reachable_fingerprint(self) -> None:
+        loop = asyncio.get_running_loop()
+
+        async def processor(reader: asyncio.StreamReader, writer: asyncio.StreamWriter) -> None:
+            try:
+                await reader.readuntil(b'\r\n\r\n')
+                writer.write(b'HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n')
+                await writer.drain()
+            except (asyncio.IncompleteReadError, ConnectionError):
+                pass  # Checks that do not send a request
+            writer.close()
+
+        with certs.ssl_context() as (ssl_ctx, _):
+            broker = await asyncio.start_server(processor, '127.0.0.1', 0, ssl=ssl_ctx)
+            try:
+                port = broker.sockets[0].getsockname()[1]
+                broker_url = f'https://127.0.0.1:{port}/uds/rest'
+                pem = await loop.run_in_executor(None, ssl.get_server_certificate, ('127.0.0.1', port))
+                fingerprint = hashlib.sha256(ssl.PEM_cert_to_DER_cert(pem)).hexdigest()
+
+                # Self signed, so only reachable if pinned (or not verified)
+                for verify_ssl, pin, reachable in (
+                    (True, '', False),
+                    (False, '', True),
+                    (True, fingerprint, True),
+                    (True, '00' * 32, False),
+                ):
+                    self.assertEqual(
+                        await loop.run_in_executor(
+                            None, metrics.broker_reachable, broker_url, verify_ssl, 2, None, pin
+                        ),
+                        reachable,
+                    )
+            finally:
+                broker.close()
+                await broker.wait_closed()
+
+    def test_broker_ready_file_backend(self) -> None:
+        with tempfile.TemporaryDirectory() as tmpdir:
+            tickets = os.path.join(tmpdir, 'tickets.json')
+            _, cfg = fixtures.get_config(uds_backend='file', uds_server=tickets)
+            # No http request is done, only the tickets file is checked
+            with mock.patch('uds_tunnel.metrics.broker_reachable') as reachable:
+                self.assertFalse(metrics.broker_ready(cfg))
+                with open(tickets, 'w', encoding='utf-8') as f:
+                    f.write('{}')
+                self.assertTrue(metrics.broker_ready(cfg))
+                reachable.assert_not_called()
@@ -0,0 +1,160 @@
+# -*- coding: utf-8 -*-
+#
+# Copyright (c) 2022 Virtual Cable S.L.U.
+# All rights reserved.
+#
+# Redistribution and use in source and binary forms, with or without modification,
+# are permitted provided that the following conditions are met:
+#
+#    * Redistributions of source code must retain the above copyright notice,
+#      this list of conditions and the following disclaimer.
+#    * Redistributions in binary form must reproduce the above copyright notice,
+#      this list of conditions and the following disclaimer in the documentation
+#      and/or other materials provided with the distribution.
+#    * Neither the name of Virtual Cable S.L. nor the names of its contributors
+#      may be used to endorse or promote products derived from this software
+#      without specific prior written permission.
+#
+# THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+# AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+# IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+# DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+# FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+# DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+# SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+# CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+# OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+# OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+'''
+Author: Adolfo Gómez, dkmaster at dkmon dot com
+'''
+import multiprocessing
+import socket
+import threading
+import time
+from unittest import TestCase, mock
+
+from udstunnel import process_connection
+from uds_tunnel import consts, processes, ratelimit
+
+
+class TestHandshakeLimiter(TestCase):
+    def test_ban_after_failures(self) -> None:
+        limiter = ratelimit.HandshakeLimiter(max_failures=5, window=60, ban_time=300)
+        for _ in range(4):
+            limiter.add_failure('1.2.3.4')
+            self.assertFalse(limiter.is_banned('1.2.3.4'))
+        limiter.add_failure('1.2.3.4')
+        self.assertTrue(limiter.is_banned('1.2.3.4'))
+        # Other sources are not affected
+        self.assertFalse(limiter.is_banned('4.3.2.1'))
+
+    def test_canonical_ip(self) -> None:
+        self.assertEqual(ratelimit.canonical_ip('1.2.3.4'), '1.2.3.4')
+        self.assertEqual(ratelimit.canonical_ip('::ffff:1.2.3.4'), '1.2.3.4')
+        self.assertEqual(ratelimit.canonical_ip('2001:DB8:0:0::1'), '2001:db8::1')
+        self.assertEqual(ratelimit.canonical_ip('fe80::1%eth0'), 'fe80::1')
+        self.assertEqual(ratelimit.canonical_ip('not an ip'), 'not an ip')
+
+    def test_ipv6_and_mapped_ipv4(self) -> None:
+        limiter = ratelimit.HandshakeLimiter(max_failures=3, window=60, ban_time=300)
+        # Mapped and plain ipv4 are the same source
+        limiter.add_failure('::ffff:1.2.3.4')
+        limiter.add_failure('1.2.3.4')
+        limiter.add_failure('::FFFF:1.2.3.4')
+        self.assertTrue(limiter.is_banned('1.2.3.4'))
+        self.assertTrue(limiter.is_banned('::ffff:1.2.3.4'))
+
+        # Different ipv6 representations are also the same source
+        limiter.add_failure('2001:db8::1')
+        limiter.add_failure('2001:DB8:0:0:0:0:0:1')
+        self.assertFalse(limiter.is_banned('2001:db8::1'))
+        limiter.add_failure('2001:0db8::0001')
+        self.assertTrue(limiter.is_banned('2001:db8:0::1'))
+        self.assertFalse(limiter.is_banned('2001:db8::2'))
+
+    def test_failures_window_and_ban_expiration(self) -> None:
+        limiter = ratelimit.HandshakeLimiter(max_failures=3, window=10, ban_time=100)
+        with mock.patch('uds_tunnel.ratelimit.time.monotonic') as monotonic:
+            monotonic.return_value = 1000.0
+            limiter.add_failure('1.2.3.4')
+            limiter.add_failure('1.2.3.4')
+            # Out of window, previous failures are forgotten
+            monotonic.return_value = 1011.0
+            limiter.add_failure('1.2.3.4')
+            self.assertFalse(limiter.is_banned('1.2.3.4'))
+            limiter.add_failure('1.2.3.4')
+            limiter.add_failure('1.2.3.4')
+            self.assertTrue(limiter.is_banned('1.2.3.4'))
+            # Ban expires
+            monotonic.return_value = 1112.0
+            self.assertFalse(limiter.is_banned('1.2.3.4'))
+
+    def test_process_connection_counts_failures(self) -> None:
+        own_conn, other_conn = multiprocessing.Pipe()  # pylint: disable=unused-variable
+        limiter = ratelimit.HandshakeLimiter(max_failures=3, window=60, ban_time=300)
+        for _ in range(3):
+            rsock, wsock = socket.socketpair()
+            rsock.settimeout(3)
+            wsock.sendall(b'invalid handshake')
+            with mock.patch('udstunnel.logger'):
+                process_connection(rsock, ('host', 'port'), own_conn, limiter)
+            wsock.close()
+        self.assertTrue(limiter.is_banned('host'))
+
+    def test_process_connection_ignores_incomplete(self) -> None:
+        own_conn, other_conn = multiprocessing.Pipe()  # pylint: disable=unused-variable
+        limiter = ratelimit.HandshakeLimiter(max_failures=1, window=60, ban_time=300)
+        ns = mock.MagicMock(handshake_failures=0)
+        # Closed without data (tcp health checks), with part of the handshake, or timed out without data
+        for data, close in ((b'', True), (consts.HANDSHAKE_V1[:2], True), (b'', False)):
+            rsock, wsock = socket.socketpair()
+            rsock.settimeout(0.1)
+            wsock.sendall(data)
+            if close:
+                wsock.close()
+            with mock.patch('udstunnel.logger'):
+                process_connection(rsock, ('host', 'port'), own_conn, limiter, ns)
+            wsock.close()
+        self.assertFalse(limiter.is_banned('host'))
+        self.assertEqual(ns.handshake_failures, 0)
+
+    def test_process_connection_handshake_deadline(self) -> None:
+        own_conn, other_conn = multiprocessing.Pipe()  # pylint: disable=unused-variable
+        limiter = ratelimit.HandshakeLimiter(max_failures=1, window=60, ban_time=300)
+        rsock, wsock = socket.socketpair()
+        rsock.settimeout(0.2)
+
+        def drip() -> None:
+            # Valid handshake, but byte by byte, each one before socket timeout
+            for byte in consts.HANDSHAKE_V1:
+                time.sleep(0.1)
+                try:
+                    wsock.send(bytes([byte]))
+                except OSError:
+                    break
+
+        sender = threading.Thread(target=drip)
+        sender.start()
+        started = time.monotonic()
+        with mock.patch('udstunnel.logger'):
+            process_connection(rsock, ('host', 'port'), own_conn, limiter)
+        # Socket timeout is for the whole handshake, not for each part of it
+        self.assertLess(time.monotonic() - started, 0.4)
+        self.assertTrue(limiter.is_banned('host'))
+        wsock.close()
+        sender.join()
+
+    def test_failed_sources_from_workers(self) -> None:
+        own_conn, other_conn = multiprocessing.Pipe()
+        with mock.patch('uds_tunnel.processes.Processes.add_child_pid'):
+            prcs = processes.Processes(mock.MagicMock(), mock.MagicMock(workers=1), mock.MagicMock())
+        prcs.children.append((own_conn, mock.MagicMock(), mock.MagicMock()))
+        # Workers report failed sources (wrong tickets, timeouts) through its pipe
+        for ip in ('1.2.3.4', '::ffff:1.2.3.4', '4.3.2.1'):
+            other_conn.send(ip)
+        self.assertEqual(prcs.failed_sources(), ['1.2.3.4', '::ffff:1.2.3.4', '4.3.2.1'])
+        self.assertEqual(prcs.failed_sources(), [])
+        # Missing workers are ignored
+        other_conn.close()
+        self.assertEqual(prcs.failed_sources(), [])
@@ -28,16 +28,20 @@
 '''
 Author: Adolfo Gómez, dkmaster at dkmon dot com
 '''
+import asyncio
+import typing
 import random
 import socket
+import threading
+import time
 import logging
 import multiprocessing
 from unittest import IsolatedAsyncioTestCase, mock
 
-from udstunnel import process_connection
-from uds_tunnel import consts
+from udstunnel import process_connection, bind_listen_socket, listen_sockaddr
+from uds_tunnel import consts, exceptions, tunnel
 
-from .utils import tuntools
+from .utils import tuntools, tools, conf
 
 logger = logging.getLogger(__name__)
 
@@ -98,7 +102,7 @@ class TestTunnel(IsolatedAsyncioTestCase):
         own_conn, other_conn = multiprocessing.Pipe()  # pylint: disable=unused-variable
 
         # Some random data to send on each test, all invalid
-        # 0 bytes will make timeout to be reached
+        # 0 bytes will make timeout to be reached (not counted as invalid)
         for i in list(range(10)) + list(range(100, 10000, 100)):
             # Create a simple socket for testing
             rsock, wsock = socket.socketpair()
@@ -112,6 +116,10 @@ class TestTunnel(IsolatedAsyncioTestCase):
                 wsock.sendall(bad_handshake)
                 process_connection(rsock, ('host', 'port'), own_conn)
 
+            if not bad_handshake:  # Timeout, not an invalid handshake
+                logger_mock.error.assert_not_called()
+                self.assertIn('HANDSHAKE not completed', logger_mock.info.call_args[0][0])
+                continue
             # Check that logger has been called
             logger_mock.error.assert_called_once()
             # And ensure that error contains 'HANDSHAKE invalid'
@@ -138,3 +146,287 @@ class TestTunnel(IsolatedAsyncioTestCase):
         # and that other_conn has received a ('host', 'port') tuple
         # recv()[0] will be a copy of the socket, we don't care about it
         self.assertEqual(other_conn.recv()[1], ('host', 'port'))
+
+    def test_split_handshake(self) -> None:
+        own_conn, other_conn = multiprocessing.Pipe()
+
+        rsock, wsock = socket.socketpair()
+        rsock.settimeout(3)
+
+        # Handshake arrives in several packets, followed by more data (TLS client hello)
+        def sender() -> None:
+            for i in range(len(consts.HANDSHAKE_V1)):
+                wsock.sendall(consts.HANDSHAKE_V1[i : i + 1])
+                time.sleep(0.01)
+            wsock.sendall(b'TLS data')
+
+        threading.Thread(target=sender).start()
+        logger_mock = mock.MagicMock()
+        with mock.patch('udstunnel.logger', logger_mock):
+            process_connection(rsock, ('host', 'port'), own_conn)
+
+        logger_mock.error.assert_not_called()
+        sock, addr = other_conn.recv()
+        self.assertEqual(addr, ('host', 'port'))
+        # Data after the handshake is not consumed
+        sock.settimeout(3)
+        self.assertEqual(sock.recv(1024), b'TLS data')
+        sock.close()
+        wsock.close()
+
+    async def test_tunnel_service_name(self) -> None:
+        # Service name provided by broker should be included in tunnel logs
+        async with tuntools.create_test_tunnel(callback=lambda x: None, port=7772, remote_port=54556) as cfg:
+            logger_mock = mock.MagicMock()
+            with mock.patch('uds_tunnel.tunnel.logger', logger_mock), mock.patch(
+                'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+                new_callable=tools.AsyncMock,
+            ) as m:
+                m.return_value = dict(
+                    conf.UDS_GET_TICKET_RESPONSE(cfg.listen_address, 54556), service='Test Service'
+                )
+                async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                    writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                    await writer.drain()
+                    self.assertEqual(await reader.read(1024), consts.RESPONSE_OK)
+
+                # Open log line contains the service name
+                open_call = next(
+                    c for c in logger_mock.info.call_args_list if c[0][0].startswith('OPEN TUNNEL')
+                )
+                self.assertEqual(open_call[0][3], ' (Test Service)')
+
+    async def test_tunnel_log_destination(self) -> None:
+        for log_destination, logged in (
+            ('full', '127.0.0.1:54559'),
+            ('redacted', '127.0.0.1'),
+            ('none', 'hidden'),
+        ):
+            async with tuntools.create_test_tunnel(
+                callback=lambda x: None, port=7784, remote_port=54559, log_destination=log_destination
+            ) as cfg:
+                logger_mock = mock.MagicMock()
+                with mock.patch('uds_tunnel.tunnel.logger', logger_mock):
+                    async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                        writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                        await writer.drain()
+                        self.assertEqual(await reader.read(1024), consts.RESPONSE_OK)
+
+                    open_call = next(
+                        c for c in logger_mock.info.call_args_list if c[0][0].startswith('OPEN TUNNEL')
+                    )
+                    self.assertEqual(open_call[0][2], logged, f'Log destination: {log_destination}')
+
+    async def test_tunnel_broker_invalid_response(self) -> None:
+        async with tuntools.create_test_tunnel(callback=lambda x: None, port=7787, remote_port=54560) as cfg:
+            with mock.patch(
+                'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+                new_callable=tools.AsyncMock,
+            ) as m:
+                m.return_value = {'host': cfg.listen_address, 'port': 54560}  # No notify ticket
+                async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                    writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                    await writer.drain()
+                    self.assertEqual(await reader.read(1024), consts.RESPONSE_ERROR_TICKET)
+
+    async def test_tunnel_errors_include_source(self) -> None:
+        async with tuntools.create_test_tunnel(callback=lambda x: None, port=7793, remote_port=54565) as cfg:
+            for error in (exceptions.BrokerUnavailableError('TICKET COMMS ERROR: down'), None):
+                with mock.patch(
+                    'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+                    new_callable=tools.AsyncMock,
+                ) as m, mock.patch('uds_tunnel.tunnel.logger') as logger_mock:
+                    if error:  # Broker failure
+                        m.side_effect = error
+                    else:  # Destination failure (nothing listening)
+                        m.return_value = conf.UDS_GET_TICKET_RESPONSE('127.0.0.1', 1)
+                    async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                        source = '{}:{}'.format(*writer.get_extra_info('sockname')[:2])
+                        writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                        await writer.drain()
+                        await reader.read(1024)
+                    await asyncio.sleep(0.1)  # Let tunnel finish
+                    errors = [c[0][0] % c[0][1:] for c in logger_mock.error.call_args_list]
+                    self.assertTrue(any(source in e for e in errors), f'{source} not in {errors}')
+
+    async def test_tunnel_broker_busy(self) -> None:
+        async def slow_ticket(cfg, *args, **kwargs) -> typing.Mapping[str, typing.Any]:
+            await asyncio.sleep(0.5)  # Overloaded broker
+            return conf.UDS_GET_TICKET_RESPONSE(cfg.listen_address, 54561)
+
+        async with tuntools.create_test_tunnel(
+            callback=lambda x: None, port=7788, remote_port=54561, uds_max_pending=1
+        ) as cfg:
+            with mock.patch('uds_tunnel.tunnel.TunnelProtocol.get_ticket_from_uds', new=slow_ticket):
+                async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                    writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                    await writer.drain()
+                    await asyncio.sleep(0.1)  # Ensure first request reached broker
+
+                    # Broker busy with first one, so second one is rejected right away
+                    async with tuntools.open_tunnel_client(cfg) as (reader2, writer2):
+                        writer2.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                        await writer2.drain()
+                        self.assertEqual(
+                            await asyncio.wait_for(reader2.read(1024), timeout=0.3), consts.RESPONSE_BUSY
+                        )
+
+                    self.assertEqual(await reader.read(1024), consts.RESPONSE_OK)
+
+    async def test_tunnel_allowed_ports(self) -> None:
+        # Destination port is 54557, only first configuration allows it
+        for allowed_ports, response in (
+            ('3389,54557', consts.RESPONSE_OK),
+            ('22,3389', consts.RESPONSE_FORBIDDEN),
+        ):
+            async with tuntools.create_test_tunnel(
+                callback=lambda x: None, port=7773, remote_port=54557, allowed_ports=allowed_ports
+            ) as cfg:
+                async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                    writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                    await writer.drain()
+                    self.assertEqual(await reader.read(1024), response, f'Allowed ports: {allowed_ports}')
+
+    async def test_tunnel_require_ip_remotes(self) -> None:
+        async with tuntools.create_test_tunnel(
+            callback=lambda x: None, port=7789, remote_port=54562, require_ip_remotes=True
+        ) as cfg:
+            for host, response in (('localhost', consts.RESPONSE_FORBIDDEN), ('127.0.0.1', consts.RESPONSE_OK)):
+                with mock.patch(
+                    'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+                    new_callable=tools.AsyncMock,
+                ) as m:
+                    m.return_value = conf.UDS_GET_TICKET_RESPONSE(host, 54562)
+                    async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                        writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                        await writer.drain()
+                        self.assertEqual(await reader.read(1024), response, f'Host: {host}')
+
+    async def test_tunnel_happy_eyeballs(self) -> None:
+        loop = asyncio.get_running_loop()
+        # Loop internals used by happy eyeballs can only be patched on asyncio loops
+        if not tunnel.TunnelProtocol.supports_happy_eyeballs(type(loop)):
+            self.skipTest('happy eyeballs not supported by event loop')
+        sock_connect = loop.sock_connect
+
+        async def getaddrinfo(host: str, port: int, *args: typing.Any, **kwargs: typing.Any) -> typing.List[typing.Any]:
+            # First address is a dead one, second is the live one
+            return [
+                (socket.AF_INET, socket.SOCK_STREAM, socket.IPPROTO_TCP, '', ('192.0.2.1', port)),
+                (socket.AF_INET, socket.SOCK_STREAM, socket.IPPROTO_TCP, '', ('127.0.0.1', port)),
+            ]
+
+        async def dead_connect(sock: socket.socket, address: typing.Any) -> None:
+            if address[0] == '192.0.2.1':
+                await asyncio.sleep(3600)  # Never answers
+            await sock_connect(sock, address)
+
+        async with tuntools.create_test_tunnel(
+            callback=lambda x: None, port=7791, remote_port=54563, happy_eyeballs_delay=0.1
+        ) as cfg:
+            with mock.patch(
+                'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+                new_callable=tools.AsyncMock,
+            ) as m, mock.patch.object(loop, 'getaddrinfo', side_effect=getaddrinfo), mock.patch.object(
+                loop, 'sock_connect', side_effect=dead_connect
+            ):
+                m.return_value = conf.UDS_GET_TICKET_RESPONSE('dualstack.example.com', 54563)
+                async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                    writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                    await writer.drain()
+                    self.assertEqual(await asyncio.wait_for(reader.read(1024), timeout=2), consts.RESPONSE_OK)
+
+    async def test_tunnel_happy_eyeballs_unsupported(self) -> None:
+        async with tuntools.create_test_tunnel(
+            callback=lambda x: None, port=7795, remote_port=54566, happy_eyeballs_delay=0.1
+        ) as cfg:
+            with mock.patch(
+                'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+                new_callable=tools.AsyncMock,
+            ) as m, mock.patch('uds_tunnel.tunnel.TunnelProtocol.supports_happy_eyeballs', return_value=False):
+                # As on uvloop, delay is ignored and hostnames are still connected
+                m.return_value = conf.UDS_GET_TICKET_RESPONSE('localhost', 54566)
+                async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                    writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                    await writer.drain()
+                    self.assertEqual(await asyncio.wait_for(reader.read(1024), timeout=2), consts.RESPONSE_OK)
+
+    async def test_tunnel_connect_timeout(self) -> None:
+        async with tuntools.create_test_tunnel(
+            callback=lambda x: None, port=7792, remote_port=54564, connect_timeout=0.5
+        ) as cfg:
+            with mock.patch(
+                'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+                new_callable=tools.AsyncMock,
+            ) as m, tools.blackholed_socket('127.0.0.1', 54567) as blackhole:
+                m.return_value = conf.UDS_GET_TICKET_RESPONSE(*blackhole.getsockname())
+                async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                    writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                    await writer.drain()
+                    start = time.monotonic()
+                    # Tunnel is closed once connect_timeout is reached
+                    self.assertEqual(await asyncio.wait_for(reader.read(1024), timeout=3), b'')
+                    self.assertLess(time.monotonic() - start, 2)
+
+    def test_listen_sockaddr(self) -> None:
+        self.assertEqual(listen_sockaddr('*', 443), (socket.AF_INET6, ('::', 443), True))
+        self.assertEqual(listen_sockaddr('0.0.0.0', 443), (socket.AF_INET, ('0.0.0.0', 443), False))  # nosec
+        self.assertEqual(listen_sockaddr('0.0.0.0', 443, True), (socket.AF_INET6, ('::', 443), False))  # nosec
+        self.assertEqual(listen_sockaddr('127.0.0.1', 443, True), (socket.AF_INET, ('127.0.0.1', 443), False))
+        self.assertEqual(listen_sockaddr('::', 443), (socket.AF_INET6, ('::', 443), False))
+        self.assertEqual(listen_sockaddr('[::1]', 443), (socket.AF_INET6, ('::1', 443), False))
+        # Hostnames are resolved
+        self.assertEqual(listen_sockaddr('localhost', 443)[1], ('127.0.0.1', 443))
+        for invalid in ('', '1.2.3', '[::1', '::g', 'nonexistent.invalid'):
+            with self.assertRaises(ValueError):
+                listen_sockaddr(invalid, 443)
+
+    def test_bind_listen_socket(self) -> None:
+        # Keep a socket bound to a port, so bind fails
+        with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as busy:
+            busy.bind(('127.0.0.1', 0))
+            busy.listen(1)
+            address = busy.getsockname()
+
+            # No retries, fails right away
+            with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as sock:
+                with mock.patch('udstunnel.time.sleep') as sleep_mock:
+                    with self.assertRaises(OSError):
+                        bind_listen_socket(sock, address, 0)
+                    sleep_mock.assert_not_called()
+
+            # With retries, fails after all of them
+            with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as sock:
+                with mock.patch('udstunnel.time.sleep') as sleep_mock:
+                    with self.assertRaises(OSError):
+                        bind_listen_socket(sock, address, 3)
+                    self.assertEqual(sleep_mock.call_count, 3)
+
+            # Port is released while retrying, bind succeeds
+            threading.Timer(0.1, busy.close).start()
+            with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as sock:
+                bind_listen_socket(sock, address, 5, delay=0.1)
+                self.assertEqual(sock.getsockname(), address)
+
+    async def test_tunnel_max_connections(self) -> None:
+        async with tuntools.create_test_tunnel(
+            callback=lambda x: None, port=7774, remote_port=54558, max_connections=1
+        ) as cfg:
+            async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                await writer.drain()
+                self.assertEqual(await reader.read(1024), consts.RESPONSE_OK)
+
+                # Limit reached, next one must be rejected
+                async with tuntools.open_tunnel_client(cfg) as (reader2, writer2):
+                    writer2.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                    await writer2.drain()
+                    self.assertEqual(await reader2.read(1024), consts.RESPONSE_BUSY)
+
+            # Give some time to tunnel to process the close
+            await asyncio.sleep(0.1)
+            # First one closed, so now there is room for a new one
+            async with tuntools.open_tunnel_client(cfg) as (reader, writer):
+                writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                await writer.drain()
+                self.assertEqual(await reader.read(1024), consts.RESPONSE_OK)
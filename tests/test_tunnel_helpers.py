@@ -28,17 +28,26 @@
 '''
 Author: Adolfo Gómez, dkmaster at dkmon dot com
 '''
+import asyncio
 import typing
 import string
 import random
 import logging
+import socket
+import os
+import ssl
+import tempfile
+import time
+import types
 
 from unittest import IsolatedAsyncioTestCase, mock
 
-from uds_tunnel import tunnel, consts
+import aiohttp
+
+from uds_tunnel import tunnel, tunnel_client, consts, exceptions, proxy
 
 from .utils import fixtures
-from .utils import tools, conf
+from .utils import tools, conf, certs, tuntools
 
 logger = logging.getLogger(__name__)
 
@@ -105,6 +114,297 @@ class TestTunnelHelpers(IsolatedAsyncioTestCase):
             # mock should have been called 100 times
             self.assertEqual(m.call_count, 100)
 
+    async def test_get_ticket_from_uds_broker_retries(self) -> None:
+        _, cfg = fixtures.get_config(uds_retries=3, uds_retry_delay=0.01, uds_timeout=10)
+        ticket = conf.NOTIFY_TICKET.encode()
+        with mock.patch(
+            'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+            new_callable=tools.AsyncMock,
+        ) as m:
+            # Broker unavailable twice, then ok
+            m.side_effect = [
+                exceptions.BrokerError('503', retryable=True),
+                exceptions.BrokerError('503', retryable=True),
+                conf.UDS_GET_TICKET_RESPONSE(*conf.REMOTE_HOST),
+            ]
+            ret_value = await tunnel.TunnelProtocol.get_ticket_from_uds(cfg, ticket, conf.CALLER_HOST)
+            self.assertEqual(ret_value, conf.UDS_GET_TICKET_RESPONSE(*conf.REMOTE_HOST))
+            self.assertEqual(m.call_count, 3)
+
+            # Non retryable errors (4xx) fail right away
+            m.reset_mock()
+            m.side_effect = exceptions.BrokerError('403', retryable=False)
+            with self.assertRaises(exceptions.BrokerError):
+                await tunnel.TunnelProtocol.get_ticket_from_uds(cfg, ticket, conf.CALLER_HOST)
+            self.assertEqual(m.call_count, 1)
+
+            # Retryable errors fail after all retries
+            m.reset_mock()
+            m.side_effect = exceptions.BrokerError('503', retryable=True)
+            with self.assertRaises(exceptions.BrokerError):
+                await tunnel.TunnelProtocol.get_ticket_from_uds(cfg, ticket, conf.CALLER_HOST)
+            self.assertEqual(m.call_count, 4)
+
+    async def test_get_ticket_from_uds_broker_deadline(self) -> None:
+        _, cfg = fixtures.get_config(uds_retries=3, uds_retry_delay=0.01, uds_timeout=1)
+        ticket = conf.NOTIFY_TICKET.encode()
+        calls = 0
+
+        # Whole request, including retries, never takes longer than uds_timeout
+        for fail_first in (False, True):
+            calls = 0
+
+            async def broker(*args, **kwargs) -> typing.Any:
+                nonlocal calls
+                calls += 1
+                if fail_first and calls == 1:  # pylint: disable=cell-var-from-loop
+                    await asyncio.sleep(0.6)
+                    raise exceptions.BrokerError('503', retryable=True)
+                await asyncio.sleep(10)  # Hangs
+
+            with mock.patch('uds_tunnel.tunnel.TunnelProtocol._read_from_uds', new=broker):
+                started = time.monotonic()
+                with self.assertRaises(exceptions.BrokerTimeoutError):
+                    await tunnel.TunnelProtocol.get_ticket_from_uds(cfg, ticket, conf.CALLER_HOST)
+                self.assertLess(time.monotonic() - started, 1.3)
+                self.assertEqual(calls, 2 if fail_first else 1)
+
+    async def test_read_from_uds_broker_timeout(self) -> None:
+        _, cfg = fixtures.get_config(uds_timeout=1)
+
+        def slow_get(url: str, **kwargs: typing.Any) -> typing.Any:
+            # Timeout must be applied to the whole request
+            self.assertEqual(kwargs['timeout'].total, 1)
+            raise asyncio.TimeoutError()
+
+        session = mock.MagicMock()
+        session.get.side_effect = slow_get
+        with mock.patch('uds_tunnel.tunnel.TunnelProtocol.get_session', return_value=session):
+            with self.assertRaises(exceptions.BrokerTimeoutError) as ctx:
+                await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+            self.assertTrue(ctx.exception.retryable)
+            self.assertIn('TIMEOUT', ctx.exception.args[0])
+
+    async def test_read_from_uds_cert_fingerprint(self) -> None:
+        for fingerprint in ('', '00' * 32):
+            _, cfg = fixtures.get_config(uds_cert_fingerprint=fingerprint, uds_verify_ssl=False)
+            session = mock.MagicMock()
+            response = session.get.return_value.__aenter__.return_value
+            response.ok = True
+            response.content.read = tools.AsyncMock(side_effect=[b'{"result": "ok"}', b''])
+            with mock.patch('uds_tunnel.tunnel.TunnelProtocol.get_session', return_value=session):
+                ret = await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+            self.assertEqual(ret, {'result': 'ok'})
+            ssl_option = session.get.call_args[1]['ssl']
+            if fingerprint:  # Pinned certificate, verify_ssl is not used
+                self.assertIsInstance(ssl_option, aiohttp.Fingerprint)
+                self.assertEqual(ssl_option.fingerprint, bytes(32))
+            else:
+                self.assertIs(ssl_option, False)
+
+    async def test_read_from_uds_client_certificate(self) -> None:
+        key, cert, _ = certs.selfSignedCert('127.0.0.1', use_password=False)
+        with tempfile.TemporaryDirectory() as tmpdir:
+            cert_file, key_file = os.path.join(tmpdir, 'client.pem'), os.path.join(tmpdir, 'client.key')
+            for filename, content in ((cert_file, cert), (key_file, key)):
+                with open(filename, 'w', encoding='utf-8') as f:
+                    f.write(content)
+
+            _, cfg = fixtures.get_config(uds_client_certificate=cert_file, uds_client_key=key_file)
+            tunnel.TunnelProtocol._ssl_context = None
+            session = mock.MagicMock()
+            response = session.get.return_value.__aenter__.return_value
+            response.ok = True
+            response.content.read = tools.AsyncMock(side_effect=[b'{"result": "ok"}', b''])
+            with mock.patch('uds_tunnel.tunnel.TunnelProtocol.get_session', return_value=session):
+                ret = await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+            self.assertEqual(ret, {'result': 'ok'})
+            ssl_option = session.get.call_args[1]['ssl']
+            self.assertIsInstance(ssl_option, ssl.SSLContext)
+            # Loaded once per process
+            self.assertIs(tunnel.TunnelProtocol.broker_ssl_context(cfg), ssl_option)
+
+            # Pinned, certificate is not verified, but fingerprint is checked on every connection
+            fingerprint = '00' * 32
+            _, cfg = fixtures.get_config(
+                uds_client_certificate=cert_file, uds_client_key=key_file, uds_cert_fingerprint=fingerprint
+            )
+            tunnel.TunnelProtocol._ssl_context = None
+            self.assertEqual(tunnel.TunnelProtocol.broker_ssl_context(cfg).verify_mode, ssl.CERT_NONE)
+            await tunnel.TunnelProtocol.close_session()
+            with mock.patch('aiohttp.TCPConnector', wraps=aiohttp.TCPConnector) as connector:
+                tunnel.TunnelProtocol.get_session(cfg)
+            pin = connector.call_args[1]['ssl']
+            self.assertIsInstance(pin, aiohttp.Fingerprint)
+            self.assertEqual(pin.fingerprint, bytes.fromhex(fingerprint))
+            await tunnel.TunnelProtocol.close_session()
+
+            # Not parseable files are reported
+            _, cfg = fixtures.get_config(uds_client_certificate=key_file, uds_client_key=cert_file)
+            tunnel.TunnelProtocol._ssl_context = None
+            with self.assertRaises(ssl.SSLError):
+                tunnel.TunnelProtocol.broker_ssl_context(cfg)
+            tunnel.TunnelProtocol._ssl_context = None
+
+    async def test_get_ticket_from_uds_source_ip(self) -> None:
+        _, cfg = fixtures.get_config()
+        with mock.patch(
+            'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+            new_callable=tools.AsyncMock,
+        ) as m:
+            m.side_effect = uds_response
+            for source, expected in (
+                ('::ffff:192.0.2.1', '192.0.2.1'),  # Mapped ipv4 from dual stack socket
+                ('2001:db8::1', '2001:db8::1'),
+                ('192.0.2.1', '192.0.2.1'),
+            ):
+                await tunnel.TunnelProtocol.get_ticket_from_uds(cfg, conf.NOTIFY_TICKET.encode(), (source, 12345))
+                self.assertEqual(m.call_args[0][2], expected)
+
+    async def test_get_ticket_from_uds_cache(self) -> None:
+        _, cfg = fixtures.get_config(uds_ticket_cache_ttl=0.2)
+        ticket = conf.NOTIFY_TICKET.encode()
+        tunnel.TunnelProtocol._ticket_cache.clear()
+        with mock.patch(
+            'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+            new_callable=tools.AsyncMock,
+        ) as m:
+            m.side_effect = uds_response
+            first = await tunnel.TunnelProtocol.get_ticket_from_uds(cfg, ticket, conf.CALLER_HOST)
+            self.assertIn('notify', first)
+            # Within ttl, broker is not asked again, but stop notification is only for first tunnel
+            cached = await tunnel.TunnelProtocol.get_ticket_from_uds(cfg, ticket, conf.CALLER_HOST)
+            self.assertEqual(cached, {k: v for k, v in first.items() if k != 'notify'})
+            self.assertEqual(m.call_count, 1)
+            # But it is for other sources
+            await tunnel.TunnelProtocol.get_ticket_from_uds(cfg, ticket, ('other', 12345))
+            self.assertEqual(m.call_count, 2)
+            # And once ttl has expired
+            await asyncio.sleep(0.3)
+            await tunnel.TunnelProtocol.get_ticket_from_uds(cfg, ticket, conf.CALLER_HOST)
+            self.assertEqual(m.call_count, 3)
+
+            # Cache size is limited
+            with mock.patch('uds_tunnel.consts.TICKET_CACHE_MAX_ENTRIES', 4):
+                for i in range(10):
+                    await tunnel.TunnelProtocol.get_ticket_from_uds(cfg, ticket, (f'host{i}', 12345))
+                self.assertEqual(len(tunnel.TunnelProtocol._ticket_cache), 4)
+        tunnel.TunnelProtocol._ticket_cache.clear()
+
+    async def test_get_ticket_from_uds_invalid_response(self) -> None:
+        _, cfg = fixtures.get_config()
+        ticket = conf.NOTIFY_TICKET.encode()
+        with mock.patch(
+            'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+            new_callable=tools.AsyncMock,
+        ) as m:
+            for response, missing in (
+                ({'host': '127.0.0.1', 'port': 22}, 'notify'),
+                ({'notify': conf.NOTIFY_TICKET}, 'host, port'),
+                ({'host': '127.0.0.1', 'port': 'ssh', 'notify': conf.NOTIFY_TICKET}, 'invalid port'),
+            ):
+                m.return_value = response
+                with self.assertRaises(exceptions.BrokerError) as ctx:
+                    await tunnel.TunnelProtocol.get_ticket_from_uds(cfg, ticket, conf.CALLER_HOST)
+                self.assertIn('TICKET INVALID RESPONSE', ctx.exception.args[0])
+                self.assertIn(missing, ctx.exception.args[0])
+                self.assertFalse(ctx.exception.retryable)
+
+    async def test_read_from_uds_broker_errors(self) -> None:
+        _, cfg = fixtures.get_config()
+        session = mock.MagicMock()
+        response = session.get.return_value.__aenter__.return_value
+        response.content.read = tools.AsyncMock(return_value=b'error')
+        with mock.patch('uds_tunnel.tunnel.TunnelProtocol.get_session', return_value=session):
+            for status, error, retryable in (
+                (404, exceptions.BrokerNotFoundError, False),
+                (401, exceptions.BrokerUnauthorizedError, False),
+                (403, exceptions.BrokerUnauthorizedError, False),
+                (500, exceptions.BrokerUnavailableError, False),
+                (503, exceptions.BrokerUnavailableError, True),
+                (400, exceptions.BrokerError, False),
+            ):
+                response.ok, response.status = False, status
+                with self.assertRaises(error) as ctx:
+                    await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+                self.assertEqual(ctx.exception.retryable, retryable, f'Status {status}')
+
+            # Error body over the limit is truncated, never read beyond it
+            huge = b'x' * (cfg.uds_max_response_size * 10)
+            response.ok, response.status = False, 500
+            response.content.read = tools.AsyncMock(side_effect=lambda n=-1: huge[: n if n >= 0 else len(huge)])
+            with self.assertRaises(exceptions.BrokerUnavailableError) as ctx:
+                await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+            self.assertEqual(response.content.read.call_args[0], (cfg.uds_max_response_size,))
+            self.assertIn('x' * cfg.uds_max_response_size, ctx.exception.args[0])
+            self.assertNotIn('x' * (cfg.uds_max_response_size + 1), ctx.exception.args[0])
+
+            # Invalid json
+            response.ok, response.status = True, 200
+            response.content.read = tools.AsyncMock(side_effect=[b'<html>', b''])
+            with self.assertRaises(exceptions.BrokerDecodeError):
+                await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+
+            # Response too big, not fully read
+            chunks = [b' ' * 1024] * (cfg.uds_max_response_size // 1024 + 1) + [b'x' * 1024] * 100
+            response.content.read = tools.AsyncMock(side_effect=chunks)
+            with self.assertRaises(exceptions.BrokerResponseTooLargeError):
+                await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+            self.assertLessEqual(response.content.read.call_count, cfg.uds_max_response_size // 1024 + 2)
+
+            # Network errors
+            session.get.side_effect = aiohttp.ClientConnectionError('Connection refused')
+            with self.assertRaises(exceptions.BrokerUnavailableError) as ctx:
+                await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+            self.assertTrue(ctx.exception.retryable)
+
+    async def test_read_from_uds_failover(self) -> None:
+        _, cfg = fixtures.get_config(uds_server='http://broker1/uds, http://broker2/uds/')
+        self.assertEqual(cfg.uds_servers, ('http://broker1/uds', 'http://broker2/uds'))
+        unavailable = mock.MagicMock(ok=False, status=503)
+        unavailable.content.read = tools.AsyncMock(return_value=b'Service unavailable')
+        available = mock.MagicMock(ok=True, status=200)
+        available.content.read = tools.AsyncMock(side_effect=[b'{"result": "ok"}', b''] * 10)
+        responses = {'http://broker1/uds': unavailable, 'http://broker2/uds': available}
+        requested: typing.List[str] = []
+
+        def get(url: str, **kwargs: typing.Any) -> mock.MagicMock:
+            server = url.split('/' + conf.NOTIFY_TICKET)[0]
+            requested.append(server)
+            ctx = mock.MagicMock()
+            ctx.__aenter__.return_value = responses[server]
+            return ctx
+
+        session = mock.MagicMock()
+        session.get.side_effect = get
+        tunnel.TunnelProtocol._broker_down.clear()
+        with mock.patch('uds_tunnel.tunnel.TunnelProtocol.get_session', return_value=session):
+            # First one is unavailable, so second one is used
+            ret = await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+            self.assertEqual(ret, {'result': 'ok'})
+            self.assertEqual(requested, ['http://broker1/uds', 'http://broker2/uds'])
+
+            # And it is skipped while marked as down, for start and stop requests
+            requested.clear()
+            await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+            await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'stop', {'sent': '1'})
+            self.assertEqual(requested, ['http://broker2/uds', 'http://broker2/uds'])
+
+            # If all of them fail, the error is returned
+            responses['http://broker2/uds'] = unavailable
+            with self.assertRaises(exceptions.BrokerUnavailableError):
+                await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+
+            # Servers answering errors (as not found) are not failed over
+            requested.clear()
+            tunnel.TunnelProtocol._broker_down.clear()
+            responses['http://broker1/uds'] = mock.MagicMock(ok=False, status=404)
+            responses['http://broker1/uds'].content.read = tools.AsyncMock(return_value=b'Not found')
+            with self.assertRaises(exceptions.BrokerNotFoundError):
+                await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+            self.assertEqual(requested, ['http://broker1/uds'])
+        tunnel.TunnelProtocol._broker_down.clear()
+
     async def test_notify_end_to_uds_broker(self) -> None:
         _, cfg = fixtures.get_config()
         with mock.patch(
@@ -133,6 +433,39 @@ class TestTunnelHelpers(IsolatedAsyncioTestCase):
             # mock should have been called 100 times
             self.assertEqual(m.call_count, 100)
 
+    async def test_notify_end_final_counters(self) -> None:
+        for uds_timeout, delay in ((2, 0.1), (1, 10)):
+            _, cfg = fixtures.get_config(uds_timeout=uds_timeout)
+            ns = types.SimpleNamespace(current=0, total=0, sent=0, recv=0)
+            owner = proxy.Proxy(cfg, ns)  # type: ignore
+            proto = tunnel.TunnelProtocol(owner)
+            proto.transport = mock.MagicMock()
+            proto.notify_ticket = conf.NOTIFY_TICKET.encode()
+            proto.stats_manager.increment_connections()
+            proto.stats_manager.add_sent(100)
+            proto.stats_manager.add_recv(50)
+            seen: typing.List[typing.Tuple[int, int, int, str]] = []
+
+            async def notify(cfg, ticket, counter) -> None:
+                await asyncio.sleep(delay)
+                seen.append((ns.current, ns.sent, ns.recv, typing.cast(str, counter.sent)))
+
+            with mock.patch('uds_tunnel.tunnel.TunnelProtocol.notify_end_to_uds', side_effect=notify):
+                proto.notify_end()
+                self.assertTrue(owner.finished.is_set())
+                # Not counted as running while broker is notified (so it does not count for max_connections)
+                self.assertEqual(ns.current, 0)
+                self.assertEqual((ns.sent, ns.recv), (100, 50))
+                self.assertIsNotNone(owner.notify_task)
+                await typing.cast('asyncio.Task[None]', owner.notify_task)
+
+            if delay < uds_timeout:
+                # Notified with final counters
+                self.assertEqual(seen, [(0, 100, 50, 100)])
+            else:
+                # Slow broker is not waited for
+                self.assertEqual(seen, [])
+
     async def test_read_from_uds_broker(self) -> None:
         # Generate a listening http server for testing UDS
         # Tesst fine responses:
@@ -166,3 +499,205 @@ class TestTunnelHelpers(IsolatedAsyncioTestCase):
                         )
                         self.assertEqual(ret, {'result': 'ok'})
 
+    async def test_broker_session_reused(self) -> None:
+        _, cfg = fixtures.get_config()
+        session = tunnel.TunnelProtocol.get_session(cfg)
+        # Same session is used for all requests
+        for _ in range(10):
+            self.assertIs(tunnel.TunnelProtocol.get_session(cfg), session)
+
+        # Once closed, a new one is created
+        await tunnel.TunnelProtocol.close_session()
+        self.assertTrue(session.closed)
+        new_session = tunnel.TunnelProtocol.get_session(cfg)
+        self.assertIsNot(new_session, session)
+        self.assertFalse(new_session.closed)
+        await tunnel.TunnelProtocol.close_session()
+
+    async def test_broker_session_loop_changed(self) -> None:
+        _, cfg = fixtures.get_config()
+
+        async def create() -> aiohttp.ClientSession:
+            return tunnel.TunnelProtocol.get_session(cfg)
+
+        # Session of another (finished) loop is not used, and it is closed when replaced
+        old_session = await asyncio.get_running_loop().run_in_executor(None, asyncio.run, create())
+        session = tunnel.TunnelProtocol.get_session(cfg)
+        self.assertIsNot(session, old_session)
+        self.assertTrue(old_session.closed)
+        await tunnel.TunnelProtocol.close_session()
+
+    async def test_broker_pending_cancelled(self) -> None:
+        _, cfg = fixtures.get_config(uds_max_pending=1)
+        owner = mock.MagicMock()
+        owner.cfg = cfg
+        owner.ns.current = 0
+        proto = tunnel.TunnelProtocol(owner)
+        proto.transport = mock.MagicMock()
+        proto.transport.is_closing.return_value = False
+        tasks = asyncio.all_tasks()
+        proto.data_received(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+        # Stopping before broker request is started must not leave it counted as pending
+        for task in asyncio.all_tasks() - tasks:
+            task.cancel()
+        await asyncio.sleep(0)
+        self.assertEqual(tunnel.TunnelProtocol.broker_pending, 0)
+
+    async def test_unexpected_error_closes_tunnel(self) -> None:
+        _, cfg = fixtures.get_config()
+        owner = mock.MagicMock()
+        owner.cfg = cfg
+        proto = tunnel.TunnelProtocol(owner)
+        proto.transport = mock.MagicMock()
+        proto.transport.is_closing.return_value = False
+        proto.runner = mock.MagicMock(side_effect=RuntimeError('Bug'))
+        with mock.patch('uds_tunnel.tunnel.logger') as logger_mock:
+            proto.data_received(b'data')
+            logger_mock.exception.assert_called_once()
+        proto.transport.close.assert_called_once()
+
+        # Errors on destination side also close the tunnel
+        client = tunnel_client.TunnelClientProtocol(proto)
+        proto.runner = proto.do_proxy
+        proto.transport.reset_mock()
+        with mock.patch.object(proto.transport, 'write', side_effect=RuntimeError('Bug')), mock.patch(
+            'uds_tunnel.tunnel_client.logger'
+        ) as logger_mock:
+            client.data_received(b'data')
+            logger_mock.exception.assert_called_once()
+        proto.transport.close.assert_called_once()
+
+    async def test_keepalive(self) -> None:
+        for interval, timeout in ((0, 30), (10, 30), (7, 30)):
+            _, cfg = fixtures.get_config(keepalive_interval=interval, keepalive_timeout=timeout)
+            with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as sock:
+                transport = mock.MagicMock()
+                transport.get_extra_info.return_value = sock
+                tunnel.TunnelProtocol.set_keepalive(cfg, transport)
+                if not interval:
+                    self.assertEqual(sock.getsockopt(socket.SOL_SOCKET, socket.SO_KEEPALIVE), 0)
+                    continue
+                self.assertNotEqual(sock.getsockopt(socket.SOL_SOCKET, socket.SO_KEEPALIVE), 0)
+                self.assertEqual(sock.getsockopt(socket.IPPROTO_TCP, socket.TCP_KEEPIDLE), interval)
+                self.assertEqual(sock.getsockopt(socket.IPPROTO_TCP, socket.TCP_KEEPINTVL), interval)
+                # Peer is dead after at least keepalive_timeout seconds without answer
+                self.assertEqual(sock.getsockopt(socket.IPPROTO_TCP, socket.TCP_KEEPCNT), -(-timeout // interval))
+
+    async def test_broker_keepalive(self) -> None:
+        _, cfg = fixtures.get_config(keepalive_interval=10, keepalive_timeout=30)
+        session = mock.MagicMock()
+        response = session.get.return_value.__aenter__.return_value
+        response.ok = True
+        response.content.read = tools.AsyncMock(side_effect=[b'{"result": "ok"}', b''])
+        with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as sock:
+            response.connection.transport = mock.MagicMock()
+            response.connection.transport.get_extra_info.return_value = sock
+            with mock.patch('uds_tunnel.tunnel.TunnelProtocol.get_session', return_value=session):
+                await tunnel.TunnelProtocol._read_from_uds(cfg, conf.NOTIFY_TICKET.encode(), 'test')
+            # Pooled broker connection is checked for dead broker as tunnels are
+            self.assertNotEqual(sock.getsockopt(socket.SOL_SOCKET, socket.SO_KEEPALIVE), 0)
+            self.assertEqual(sock.getsockopt(socket.IPPROTO_TCP, socket.TCP_KEEPIDLE), 10)
+
+    async def test_invalid_and_malformed_commands(self) -> None:
+        _, cfg = fixtures.get_config()
+        owner = mock.MagicMock()
+        owner.cfg = cfg
+        owner.ns = types.SimpleNamespace(invalid_commands=0, malformed_commands=0)
+        for data, invalid, malformed in (
+            (b'XXXX', 1, 0),  # Unknown command
+            (b'XXXX' + b'x' * 1024, 2, 0),
+            (consts.COMMAND_OPEN + tuntools.get_correct_ticket() + b'extra data', 2, 1),  # Too long
+            (consts.COMMAND_STAT + b'x' * (consts.PASSWORD_LENGTH + 1024), 2, 2),
+            (consts.COMMAND_TEST + b'x', 2, 3),
+        ):
+            proto = tunnel.TunnelProtocol(owner)
+            proto.transport = mock.MagicMock()
+            proto.transport.is_closing.return_value = False
+            proto.data_received(data)
+            proto.transport.write.assert_called_once_with(consts.RESPONSE_ERROR_COMMAND)
+            proto.transport.close.assert_called_once()
+            self.assertEqual((owner.ns.invalid_commands, owner.ns.malformed_commands), (invalid, malformed))
+
+    async def test_write_watchdog(self) -> None:
+        _, cfg = fixtures.get_config(write_timeout=0.1)
+        owner = mock.MagicMock()
+        owner.cfg = cfg
+        for resume in (False, True):
+            proto = tunnel.TunnelProtocol(owner)
+            proto.transport = mock.MagicMock()
+            proto.transport.is_closing.return_value = False
+            proto.transport.get_write_buffer_size.return_value = 65536
+            # Write buffer is full, watchdog is armed
+            proto.pause_writing()
+            await asyncio.sleep(0.05)
+            if resume:  # Data flushed in time
+                proto.resume_writing()
+            await asyncio.sleep(0.1)
+            # If not resumed, connection must have been closed by watchdog
+            self.assertEqual(proto.transport.close.called, not resume)
+
+        # Slow writer, but draining, is not closed
+        proto = tunnel.TunnelProtocol(owner)
+        proto.transport = mock.MagicMock()
+        proto.transport.is_closing.return_value = False
+        proto.transport.get_write_buffer_size.side_effect = range(65536, 0, -1024)
+        proto.pause_writing()
+        await asyncio.sleep(0.35)
+        proto.transport.close.assert_not_called()
+        proto.stop_write_watchdog()
+
+    def test_supports_happy_eyeballs(self) -> None:
+        self.assertTrue(tunnel.TunnelProtocol.supports_happy_eyeballs(asyncio.SelectorEventLoop))
+        try:
+            import uvloop  # pylint: disable=import-outside-toplevel
+        except ImportError:
+            self.skipTest('uvloop not installed')
+        self.assertFalse(tunnel.TunnelProtocol.supports_happy_eyeballs(uvloop.Loop))
+
+    async def test_throttle(self) -> None:
+        _, cfg = fixtures.get_config(tunnel_bandwidth_limit=1000)
+        owner = mock.MagicMock()
+        owner.cfg = cfg
+        owner.bandwidth = None
+        proto = tunnel.TunnelProtocol(owner)
+        proto.transport = mock.MagicMock()
+        proto.transport.is_closing.return_value = False
+        proto.pause_reading('open')
+        # Over the limit, reading stays paused for the time needed to honor it
+        proto.consume_bandwidth(proto, 1100)
+        proto.consume_bandwidth(proto, 100)
+        await asyncio.sleep(0.25)
+        # Still waiting for open, so reading is not resumed by bandwidth limits
+        proto.transport.resume_reading.assert_not_called()
+        self.assertEqual(proto.read_paused_by, {'open'})
+        proto.resume_reading('open')
+        proto.transport.resume_reading.assert_called_once()
+
+    async def test_write_backpressure(self) -> None:
+        _, cfg = fixtures.get_config()
+        owner = mock.MagicMock()
+        owner.cfg = cfg
+        proto = tunnel.TunnelProtocol(owner)
+        proto.transport = mock.MagicMock()
+        proto.transport.is_closing.return_value = False
+        client_transport = mock.MagicMock()
+        client_transport.is_closing.return_value = False
+        proto.client = tunnel_client.TunnelClientProtocol(proto)
+        proto.client.connection_made(client_transport)
+        client_transport.pause_reading.assert_called_once()  # Until open
+        proto.client.resume_reading('open')
+        client_transport.resume_reading.assert_called_once()
+
+        # While client can't write, destination is not read
+        proto.pause_writing()
+        self.assertEqual(client_transport.pause_reading.call_count, 2)
+        proto.resume_writing()
+        self.assertEqual(client_transport.resume_reading.call_count, 2)
+
+        # And the other way around, but only resumed when nothing else keeps it paused
+        proto.pause_reading('open')
+        proto.client.pause_writing()
+        proto.client.resume_writing()
+        proto.transport.resume_reading.assert_not_called()
+        proto.resume_reading('open')
+        proto.transport.resume_reading.assert_called_once()
@@ -29,13 +29,21 @@
 Author: Adolfo Gómez, dkmaster at dkmon dot com
 '''
 import random
+import signal
 import asyncio
+import contextlib
+import json
+import os
+import tempfile
+import time
+import typing
 import logging
-from unittest import IsolatedAsyncioTestCase
+from unittest import IsolatedAsyncioTestCase, mock
 
-from uds_tunnel import consts
+import udstunnel
+from uds_tunnel import consts, exceptions, stats
 
-from .utils import tuntools, tools
+from .utils import tuntools, tools, conf
 
 logger = logging.getLogger(__name__)
 
@@ -54,6 +62,14 @@ class TestUDSTunnelMainProc(IsolatedAsyncioTestCase):
             self.assertEqual(stderr, b'')
             self.assertIn(b'usage: udstunnel', stdout)
 
+    async def test_run_app_port_in_use(self) -> None:
+        # Port already in use, app must exit with bind error code
+        with tools.bound_socket('127.0.0.1', 7892):
+            async with tuntools.tunnel_app_runner('127.0.0.1', 7892) as process:
+                stdout, stderr = await process.communicate()
+                self.assertEqual(process.returncode, consts.EXIT_BIND_ERROR, f'{stdout!r} {stderr!r}')
+                self.assertIn(b'could not listen', stderr)
+
     async def test_tunnel_fail_cmd(self) -> None:
         # Test on ipv4 and ipv6
         for host in ('::1', '127.0.0.1'):
@@ -246,6 +262,357 @@ class TestUDSTunnelMainProc(IsolatedAsyncioTestCase):
                         data = await creader.read(1024)
                         self.assertEqual(data, b'', f'Tunnel host: {tunnel_host}, server host: {host}')
 
+    async def test_tunnel_shutdown_grace(self) -> None:
+        received: bytes = b''
+
+        def callback(data: bytes) -> bytes:
+            nonlocal received
+            received += data
+            return data  # Echo
+
+        async with tools.AsyncTCPServer(host='127.0.0.1', port=5446, callback=callback) as server:
+            async with tuntools.create_tunnel_proc(
+                '127.0.0.1',
+                7780,
+                server.host,
+                server.port,
+                shutdown_grace=10,
+            ) as (cfg, _):
+                async with tuntools.open_tunnel_client(cfg) as (creader, cwriter):
+                    cwriter.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                    await cwriter.drain()
+                    self.assertEqual(await creader.read(1024), consts.RESPONSE_OK)
+
+                    # Start draining, give time to tunnel process to notice it
+                    udstunnel.do_stop.set()
+                    await asyncio.sleep(3)
+
+                    # New connections are not accepted anymore
+                    with self.assertRaises(Exception):
+                        async with tuntools.open_tunnel_client(cfg) as (creader2, cwriter2):
+                            cwriter2.write(consts.COMMAND_TEST)
+                            await cwriter2.drain()
+                            await creader2.read(1024)
+
+                    # But running tunnel keeps working
+                    cwriter.write(b'Some data')
+                    await cwriter.drain()
+                    self.assertEqual(await creader.read(1024), b'Some data')
+                    self.assertEqual(received, b'Some data')
+
+    async def test_tunnel_shutdown_notifies_broker(self) -> None:
+        async with tools.AsyncTCPServer(host='127.0.0.1', port=5453, callback=lambda data: data) as server:
+            async with tuntools.create_tunnel_proc(
+                '127.0.0.1',
+                7794,
+                server.host,
+                server.port,
+                shutdown_grace=1,
+            ) as (cfg, _):
+                with mock.patch(
+                    'uds_tunnel.tunnel.TunnelProtocol._read_from_uds',
+                    new_callable=tools.AsyncMock,
+                ) as m:
+                    m.return_value = conf.UDS_GET_TICKET_RESPONSE(server.host, server.port)
+                    async with tuntools.open_tunnel_client(cfg) as (creader, cwriter):
+                        cwriter.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                        await cwriter.drain()
+                        self.assertEqual(await creader.read(1024), consts.RESPONSE_OK)
+
+                        # Stop (as on SIGTERM) while tunnel is still running, so it is closed after grace
+                        udstunnel.stop_signal(signal.SIGTERM, None)
+                        # Closed by the tunnel server, not by us
+                        self.assertEqual(await asyncio.wait_for(creader.read(1024), timeout=8), b'')
+
+                    # Broker has been notified of the closed tunnel
+                    self.assertEqual(m.call_count, 2)
+                    self.assertEqual(m.call_args.args[2], 'stop')
+
+    async def test_tunnel_early_banner(self) -> None:
+        banner = b'SSH-2.0-Banner\r\n'
+
+        async def processor(reader: asyncio.StreamReader, writer: asyncio.StreamWriter) -> None:
+            # Server speaks first, as soon as connection is made
+            writer.write(banner)
+            await writer.drain()
+            writer.write(await reader.read(1024))
+            await writer.drain()
+
+        async with tools.AsyncTCPServer(host='127.0.0.1', port=5448, processor=processor) as server:
+            async with tuntools.create_tunnel_proc(
+                '127.0.0.1',
+                7782,
+                server.host,
+                server.port,
+            ) as (cfg, _):
+                async with tuntools.open_tunnel_client(cfg) as (creader, cwriter):
+                    cwriter.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                    await cwriter.drain()
+                    # OK must come first, and then the banner
+                    self.assertEqual(await creader.readexactly(len(consts.RESPONSE_OK)), consts.RESPONSE_OK)
+                    self.assertEqual(await creader.readexactly(len(banner)), banner)
+
+                    cwriter.write(b'Some data')
+                    await cwriter.drain()
+                    self.assertEqual(await creader.read(1024), b'Some data')
+
+    async def test_tunnel_client_closed_before_open(self) -> None:
+        connections = 0
+
+        async def processor(reader: asyncio.StreamReader, writer: asyncio.StreamWriter) -> None:
+            nonlocal connections
+            connections += 1
+            await reader.read(1024)
+
+        requests: typing.List[str] = []
+
+        async def slow_broker(cfg, ticket, msg, *args, **kwargs) -> typing.Mapping[str, typing.Any]:
+            requests.append(msg)
+            if msg != 'stop':
+                await asyncio.sleep(0.4)  # Slow broker
+            return conf.UDS_GET_TICKET_RESPONSE(server.host, server.port)
+
+        stats_collector = stats.GlobalStats()
+        async with tools.AsyncTCPServer(host='127.0.0.1', port=5449, processor=processor) as server:
+            async with tuntools.create_tunnel_proc(
+                '127.0.0.1',
+                7783,
+                server.host,
+                server.port,
+                global_stats=stats_collector,
+            ) as (cfg, _):
+                with mock.patch('uds_tunnel.tunnel.TunnelProtocol._read_from_uds', new=slow_broker):
+                    async with tuntools.open_tunnel_client(cfg) as (creader, cwriter):
+                        cwriter.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                        await cwriter.drain()
+                    # Client closed while waiting for broker
+                    await asyncio.sleep(0.8)
+
+        # No connection to destination should have been made
+        self.assertEqual(connections, 0)
+        self.assertEqual(stats_collector.ns.current, 0)
+        # But broker, that has registered the start, knows it has finished
+        self.assertEqual(requests, [server.host, 'stop'])
+
+    async def test_tunnel_failed_handshakes(self) -> None:
+        def response(ticket: bytes) -> typing.Mapping[str, typing.Any]:
+            if ticket.endswith(b'unknown'):
+                raise exceptions.BrokerNotFoundError('TICKET COMMS ERROR: not found')
+            return conf.UDS_GET_TICKET_RESPONSE(server.host, server.port)
+
+        stats_collector = stats.GlobalStats()
+        failed_sources: typing.List[str] = []
+        async with tools.AsyncTCPServer(host='127.0.0.1', port=5454, callback=lambda data: None) as server:
+            async with tuntools.create_tunnel_proc(
+                '127.0.0.1',
+                7796,
+                server.host,
+                server.port,
+                response=response,
+                global_stats=stats_collector,
+                failed_sources=failed_sources,
+                command_timeout=0.3,
+                handshake_max_failures=10,
+            ) as (cfg, _):
+                # Successful open and test commands are not failures
+                for command, expected in (
+                    (consts.COMMAND_OPEN + tuntools.get_correct_ticket(), consts.RESPONSE_OK),
+                    (consts.COMMAND_TEST, consts.RESPONSE_OK),
+                ):
+                    async with tuntools.open_tunnel_client(cfg) as (creader, cwriter):
+                        cwriter.write(command)
+                        await cwriter.drain()
+                        self.assertEqual(await creader.read(1024), expected)
+                # Neither are clients closing before sending a command
+                async with tuntools.open_tunnel_client(cfg) as (creader, cwriter):
+                    pass
+                await asyncio.sleep(0.5)  # More than command_timeout
+                self.assertEqual(stats_collector.ns.handshake_failures, 0)
+
+                # Unknown or malformed tickets and timeouts are
+                for command, expected in (
+                    (consts.COMMAND_OPEN + tuntools.get_correct_ticket(prefix='unknown'), consts.RESPONSE_ERROR_TICKET),
+                    (consts.COMMAND_OPEN + b'-' * consts.TICKET_LENGTH, consts.RESPONSE_ERROR_TICKET),
+                    (b'', consts.RESPONSE_ERROR_TIMEOUT),
+                ):
+                    async with tuntools.open_tunnel_client(cfg) as (creader, cwriter):
+                        cwriter.write(command)
+                        await cwriter.drain()
+                        self.assertEqual(await creader.read(1024), expected)
+                self.assertEqual(stats_collector.ns.handshake_failures, 3)
+
+        # And are reported to be counted by the handshake limiter
+        self.assertEqual(failed_sources, ['127.0.0.1'] * 3)
+
+    async def test_tunnel_bandwidth_limit(self) -> None:
+        received = 0
+        all_received = asyncio.Event()
+        data = b'x' * 1500000
+        limit = 1000000  # bytes per second
+
+        def callback(chunk: bytes) -> None:
+            nonlocal received
+            received += len(chunk)
+            if received >= len(data) * 2:
+                all_received.set()
+
+        async with tools.AsyncTCPServer(host='127.0.0.1', port=5450, callback=callback) as server:
+            async with tuntools.create_tunnel_proc(
+                '127.0.0.1',
+                7785,
+                server.host,
+                server.port,
+                bandwidth_limit=limit,
+                workers=1,
+            ) as (cfg, _):
+                async with tuntools.open_tunnel_client(cfg) as (creader1, cwriter1):
+                    async with tuntools.open_tunnel_client(cfg) as (creader2, cwriter2):
+                        for reader, writer in ((creader1, cwriter1), (creader2, cwriter2)):
+                            writer.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                            await writer.drain()
+                            self.assertEqual(await reader.read(1024), consts.RESPONSE_OK)
+
+                        start = time.monotonic()
+                        # Both tunnels share the limit
+                        cwriter1.write(data)
+                        cwriter2.write(data)
+                        await asyncio.wait_for(all_received.wait(), timeout=10)
+                        elapsed = time.monotonic() - start
+
+        # One second of burst is allowed, the rest is limited (but last read of each tunnel is not waited for)
+        self.assertEqual(received, len(data) * 2)
+        self.assertGreaterEqual(elapsed, (len(data) * 2 - limit - 2 * tuntools.MAX_READ_SIZE) / limit)
+
+    async def test_tunnel_bandwidth_limit_per_tunnel(self) -> None:
+        received = 0
+        all_received = asyncio.Event()
+        data = b'x' * 3000000
+        limit = 1000000  # bytes per second
+
+        def callback(chunk: bytes) -> None:
+            nonlocal received
+            received += len(chunk)
+            if received >= len(data):
+                all_received.set()
+
+        async with tools.AsyncTCPServer(host='127.0.0.1', port=5451, callback=callback) as server:
+            async with tuntools.create_tunnel_proc(
+                '127.0.0.1',
+                7786,
+                server.host,
+                server.port,
+                tunnel_bandwidth_limit=limit,
+            ) as (cfg, _):
+                async with tuntools.open_tunnel_client(cfg) as (creader, cwriter):
+                    cwriter.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                    await cwriter.drain()
+                    self.assertEqual(await creader.read(1024), consts.RESPONSE_OK)
+
+                    start = time.monotonic()
+                    cwriter.write(data)
+                    await asyncio.wait_for(all_received.wait(), timeout=10)
+                    elapsed = time.monotonic() - start
+
+        # One second of burst is allowed, the rest is limited (but last read is not waited for)
+        self.assertEqual(received, len(data))
+        self.assertGreaterEqual(elapsed, (len(data) - limit - tuntools.MAX_READ_SIZE) / limit)
+
+    async def test_app_bandwidth_limit_shared_by_workers(self) -> None:
+        received = expected = 0
+        all_received = asyncio.Event()
+        limit = 1000000  # bytes per second
+
+        def callback(chunk: bytes) -> None:
+            nonlocal received
+            received += len(chunk)
+            if received >= expected:
+                all_received.set()
+
+        tickets = [tuntools.get_correct_ticket() for _ in range(2)]
+        async with tools.AsyncTCPServer(host='127.0.0.1', port=5455, callback=callback) as server:
+            with tempfile.TemporaryDirectory() as tmpdir:
+                tickets_file = os.path.join(tmpdir, 'tickets.json')
+                with open(tickets_file, 'w', encoding='utf-8') as f:
+                    json.dump(
+                        {ticket.decode(): conf.UDS_GET_TICKET_RESPONSE(server.host, server.port) for ticket in tickets},
+                        f,
+                    )
+                async with tuntools.tunnel_app_runner(
+                    '127.0.0.1',
+                    7797,
+                    wait_for_port=True,
+                    uds_backend='file',
+                    uds_server=tickets_file,
+                    bandwidth_limit=limit,
+                    workers=2,
+                    user='',  # Keep running user, even if root
+                ):
+                    cfg = mock.MagicMock(ipv6=False, listen_address='127.0.0.1', listen_port=7797)
+                    # Whole limit is available to a single tunnel, and shared by several ones (wherever they run)
+                    for data in ([b'x' * 4000000], [b'x' * 2000000] * 2):
+                        received, expected = 0, sum(len(d) for d in data)
+                        all_received.clear()
+                        await asyncio.sleep(1)  # Refill burst
+                        async with contextlib.AsyncExitStack() as stack:
+                            clients = [
+                                await stack.enter_async_context(
+                                    tuntools.open_tunnel_client(cfg, use_tunnel_handshake=True)
+                                )
+                                for _ in data
+                            ]
+                            for (reader, writer), ticket in zip(clients, tickets):
+                                writer.write(consts.COMMAND_OPEN + ticket)
+                                await writer.drain()
+                                self.assertEqual(await reader.read(1024), consts.RESPONSE_OK)
+
+                            start = time.monotonic()
+                            for (_, writer), chunk in zip(clients, data):
+                                writer.write(chunk)
+                            await asyncio.wait_for(all_received.wait(), timeout=20)
+                            elapsed = time.monotonic() - start
+
+                        # One second of burst is allowed, the rest is limited (but last read of each tunnel is not waited for)
+                        self.assertGreaterEqual(elapsed, (expected - limit - len(data) * tuntools.MAX_READ_SIZE) / limit)
+                        self.assertLess(elapsed, (expected - limit) / limit * 1.5)
+
+    async def test_tunnel_file_backend(self) -> None:
+        received: bytes = b''
+        data_received = asyncio.Event()
+
+        def callback(data: bytes) -> None:
+            nonlocal received
+            received += data
+            data_received.set()
+
+        ticket = tuntools.get_correct_ticket()
+        async with tools.AsyncTCPServer(host='127.0.0.1', port=5452, callback=callback) as server:
+            with tempfile.TemporaryDirectory() as tmpdir:
+                tickets_file = os.path.join(tmpdir, 'tickets.json')
+                with open(tickets_file, 'w', encoding='utf-8') as f:
+                    json.dump({ticket.decode(): conf.UDS_GET_TICKET_RESPONSE(server.host, server.port)}, f)
+
+                async with tuntools.create_tunnel_proc(
+                    '127.0.0.1',
+                    7790,
+                    uds_backend='file',
+                    uds_server=tickets_file,
+                ) as (cfg, _):
+                    # Known ticket, tunnel works as with a real broker
+                    async with tuntools.open_tunnel_client(cfg) as (creader, cwriter):
+                        cwriter.write(consts.COMMAND_OPEN + ticket)
+                        await cwriter.drain()
+                        self.assertEqual(await creader.read(1024), consts.RESPONSE_OK)
+                        cwriter.write(b'Some data')
+                        await cwriter.drain()
+                        await asyncio.wait_for(data_received.wait(), timeout=2)
+                    self.assertEqual(received, b'Some data')
+
+                    # Unknown ticket
+                    async with tuntools.open_tunnel_client(cfg) as (creader, cwriter):
+                        cwriter.write(consts.COMMAND_OPEN + tuntools.get_correct_ticket())
+                        await cwriter.drain()
+                        self.assertEqual(await creader.read(1024), consts.RESPONSE_ERROR_TICKET)
+
     async def test_tunnel_invalid_ssl_handshake(self) -> None:
         for tunnel_host in ('127.0.0.1', '::1'):
             async with tuntools.create_tunnel_proc(
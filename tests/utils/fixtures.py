@@ -54,6 +54,12 @@ logsize = {logsize}
 # Number of backup logs to keep. Defaults to 3
 lognumber = {lognumber}
 
+# Log format, text or json (one json object per line). Defaults to text
+logformat = {logformat}
+
+# How tunnel destinations are logged: full, redacted or none
+log_destination = {log_destination}
+
 # Listen address. Defaults to 0.0.0.0
 address = {address}
 
@@ -63,9 +69,23 @@ ipv6 = {ipv6}
 # Listen port. Defaults to 443
 port = {port}
 
+# Number of retries if port can't be bound. Defaults to 0
+bind_retries = {bind_retries}
+
 # Number of workers. Defaults to  0 (means "as much as cores")
 workers = {workers}
 
+# Max number of simultaneous tunnels. Defaults to 0 (no limit)
+max_connections = {max_connections}
+
+# Time given to running tunnels to finish on stop. Defaults to 0
+shutdown_grace = {shutdown_grace}
+
+# Max bandwidth for all tunnels, in bytes per second. Defaults to 0 (no limit)
+bandwidth_limit = {bandwidth_limit}
+# Max bandwidth for each tunnel, in bytes per second. Defaults to 0 (no limit)
+tunnel_bandwidth_limit = {tunnel_bandwidth_limit}
+
 # SSL Related parameters. 
 ssl_certificate = {ssl_certificate}
 ssl_certificate_key = {ssl_certificate_key}
@@ -79,10 +99,19 @@ ssl_dhparam = {ssl_dhparam}
 # Valid examples:
 #  http://www.example.com/uds/rest/tunnel/ticket
 #  https://www.example.com:14333/uds/rest/tunnel/ticket
+uds_backend = {uds_backend}
 uds_server = {uds_server}
 uds_token = {uds_token}
 uds_timeout = {uds_timeout}
 uds_verify_ssl = {uds_verify_ssl}
+uds_cert_fingerprint = {uds_cert_fingerprint}
+uds_client_certificate = {uds_client_certificate}
+uds_client_key = {uds_client_key}
+uds_retries = {uds_retries}
+uds_retry_delay = {uds_retry_delay}
+uds_max_pending = {uds_max_pending}
+uds_max_response_size = {uds_max_response_size}
+uds_ticket_cache_ttl = {uds_ticket_cache_ttl}
 
 # Secret to get access to admin commands (Currently only stats commands). No default for this.
 # Admin commands and only allowed from "allow" ips
@@ -94,10 +123,39 @@ secret = {secret}
 # defaults to localhost (change if listen address is different from 0.0.0.0)
 allow = {allow}
 
+# List of allowed destination ports, separated by commas. Empty means any port.
+allowed_ports = {allowed_ports}
+
+# If destinations must be ip addresses. Defaults to false
+require_ip_remotes = {require_ip_remotes}
+
+# Delay between connection attempts to each address of a destination. 0 disables it
+happy_eyeballs_delay = {happy_eyeballs_delay}
+
+# Max time to connect to a destination. 0 means system default
+connect_timeout = {connect_timeout}
+
 # Command timeout. Command reception on tunnel will timeout after this time (in seconds)
 # defaults to 3 seconds
 command_timeout = {command_timeout}
 
+# Write timeout. If a side of the tunnel does not accept data for this time (in seconds),
+# the tunnel will be closed. 0 disables it. Defaults to 0
+write_timeout = {write_timeout}
+
+# Dead peers detection. 0 interval disables it
+keepalive_interval = {keepalive_interval}
+keepalive_timeout = {keepalive_timeout}
+
+# Invalid handshakes limits. 0 max failures disables it
+handshake_max_failures = {handshake_max_failures}
+handshake_failures_window = {handshake_failures_window}
+handshake_ban_time = {handshake_ban_time}
+
+# Metrics (prometheus format) listener. Empty address disables it
+metrics_address = {metrics_address}
+metrics_port = {metrics_port}
+
 use_uvloop = {use_uvloop}
 '''
 
@@ -110,22 +168,50 @@ def get_config(**overrides) -> typing.Tuple[typing.Dict[str, typing.Any], config
         'logfile': f'/tmp/uds_tunnel_{random.randint(0, 100)}.log',  # Random log file
         'logsize': random.randint(0, 100),  # Random log size
         'lognumber': random.randint(0, 100),  # Random log number
+        'logformat': random.choice(['text', 'json']),  # Random log format
+        'log_destination': random.choice(['full', 'redacted', 'none']),  # Random destination log mode
         'address': f'{random.randint(0, 255)}.{random.randint(0, 255)}.{random.randint(0, 255)}.{random.randint(0, 255)}',  # Random address
         'port': random.randint(0, 65535),  # Random port
+        'bind_retries': 0,  # Do not retry bind on tests
         'ipv6': random.choice([True, False]),  # Random ipv6
         'workers': random.randint(1, 100),  # Random workers, 0 will return as many as cpu cores
+        'max_connections': 0,  # No limit
+        'shutdown_grace': random.randint(0, 100),  # Random shutdown grace
+        'bandwidth_limit': 0,  # No bandwidth limit
+        'tunnel_bandwidth_limit': 0,  # No bandwidth limit
         'ssl_certificate': f'/tmp/uds_tunnel_{rand_number}.crt',  # Random ssl certificate
         'ssl_certificate_key': f'/tmp/uds_tunnel_{rand_number}.key',  # Random ssl certificate key
         'ssl_password': f'password{random.randint(0, 100)}',  # Random ssl password
         'ssl_ciphers': f'ciphers{random.randint(0, 100)}',  # Random ssl ciphers
         'ssl_dhparam': f'/tmp/uds_tunnel_{rand_number}.dh',  # Random ssl dhparam
+        'uds_backend': 'http',  # Real broker
         'uds_server': f'https://uds_server{rand_number}/some_path',  # Random uds server
         'uds_token': f'uds_token{"".join(random.choices(string.ascii_uppercase + string.digits, k=32))}',  # Random uds token
         'uds_timeout': random.randint(0, 100),  # Random uds timeout
         'uds_verify_ssl': random.choice([True, False]),  # Random verify uds ssl
+        'uds_cert_fingerprint': '',  # No pinning
+        'uds_client_certificate': '',  # No client certificate
+        'uds_client_key': '',
+        'uds_retries': random.randint(0, 10),  # Random uds retries
+        'uds_retry_delay': random.randint(1, 10) / 10,  # Random uds retry delay
+        'uds_max_pending': 0,  # No limit
+        'uds_max_response_size': random.randint(1024, 65536),  # Random max response size
+        'uds_ticket_cache_ttl': 0,  # No ticket cache
         'secret': f'secret{random.randint(0, 100)}',  # Random secret
         'allow': f'{random.randint(0, 255)}.0.0.0',  # Random allow
+        'allowed_ports': '',  # No restriction on destination ports
+        'require_ip_remotes': False,  # Hostnames allowed
+        'happy_eyeballs_delay': 0,  # Connect as usual
+        'connect_timeout': random.randint(0, 100),  # Random connect timeout
         'command_timeout': random.randint(0, 100),  # Random command timeout
+        'write_timeout': random.randint(0, 100),  # Random write timeout
+        'keepalive_interval': 0,  # No keepalive on tests
+        'keepalive_timeout': random.randint(1, 100),  # Random keepalive timeout
+        'handshake_max_failures': 0,  # Do not ban on tests
+        'handshake_failures_window': random.randint(1, 100),  # Random failures window
+        'handshake_ban_time': random.randint(1, 1000),  # Random ban time
+        'metrics_address': '',  # No metrics listener on tests
+        'metrics_port': random.randint(1024, 65535),  # Random metrics port
         'use_uvloop': random.choice([True, False]),  # Random use uvloop
     }
     values.update(overrides)
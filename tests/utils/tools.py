@@ -29,6 +29,7 @@
 Author: Adolfo Gómez, dkmaster at dkmon dot com
 '''
 import asyncio
+import contextlib
 import os
 import ssl
 import typing
@@ -58,6 +59,40 @@ def get_free_port(ipv6: bool = False) -> int:
     s.close()
     return port
 
+@contextlib.contextmanager
+def bound_socket(host: str, port: int) -> typing.Generator[socket.socket, None, None]:
+    '''Keeps host:port bound (and listening) while in context
+    '''
+    s = socket.socket(socket.AF_INET6 if ':' in host else socket.AF_INET, socket.SOCK_STREAM)
+    try:
+        s.bind((host, port))
+        s.listen(1)
+        yield s
+    finally:
+        s.close()
+
+
+@contextlib.contextmanager
+def blackholed_socket(host: str, port: int) -> typing.Generator[socket.socket, None, None]:
+    '''Keeps host:port listening, but never accepting, while in context.
+    Its backlog is full, so new connections to it hang (as with an unreachable host)
+    '''
+    family = socket.AF_INET6 if ':' in host else socket.AF_INET
+    fillers: typing.List[socket.socket] = []
+    with bound_socket(host, port) as s:
+        s.listen(0)
+        try:
+            for _ in range(3):
+                filler = socket.socket(family, socket.SOCK_STREAM)
+                filler.setblocking(False)
+                fillers.append(filler)
+                filler.connect_ex((host, port))
+            yield s
+        finally:
+            for filler in fillers:
+                filler.close()
+
+
 class AsyncMock(mock.MagicMock):
     async def __call__(self, *args, **kwargs):  # pylint: disable=invalid-overridden-method
         return super().__call__(*args, **kwargs)
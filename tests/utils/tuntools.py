@@ -51,6 +51,9 @@ from . import certs, conf, fixtures, tools
 
 logger = logging.getLogger(__name__)
 
+# Max data read at once from a tls transport (asyncio ssl buffer size)
+MAX_READ_SIZE: typing.Final[int] = 256 * 1024
+
 if typing.TYPE_CHECKING:
     from asyncio.subprocess import Process
 
@@ -120,6 +123,7 @@ async def create_tunnel_proc(
     ] = None,
     use_fake_http_server: bool = False,
     global_stats: typing.Optional[stats.GlobalStats] = None,
+    failed_sources: typing.Optional[typing.List[str]] = None,
     # Configuration parameters
     **kwargs,
 ) -> collections.abc.AsyncGenerator[
@@ -136,6 +140,8 @@ async def create_tunnel_proc(
         remote_port (int): Remote port to connect to
         response (typing.Optional[typing.Union[typing.Callable[[bytes], typing.Mapping[str, typing.Any]], typing.Mapping[str, typing.Any]]], optional): Response to send to the client. Defaults to None.
         use_fake_http_server (bool, optional): If True, a fake http server will be used instead of a mock. Defaults to False.
+        failed_sources (typing.Optional[typing.List[str]], optional): If provided, sources reported as failed by the tunnel
+            (only if handshake_max_failures is set) are appended to it on exit. Defaults to None.
 
     Yields:
         collections.abc.AsyncGenerator[typing.Tuple[config.ConfigurationType, typing.Optional[asyncio.Queue[bytes]]], None]: A tuple with the configuration
@@ -165,6 +171,12 @@ async def create_tunnel_proc(
                 finally:
                     pass
 
+    elif args.get('uds_backend') == 'file':  # Tickets are resolved by tunnel itself
+
+        @contextlib.asynccontextmanager
+        async def provider() -> collections.abc.AsyncGenerator[typing.Optional[asyncio.Queue[bytes]], None]:
+            yield None
+
     else:
 
         @contextlib.asynccontextmanager
@@ -238,6 +250,10 @@ async def create_tunnel_proc(
                 server_task.cancel()
                 logger.info('Server closed')
 
+                # Get failures reported back by tunnel, as main process does
+                while failed_sources is not None and own_end.poll():
+                    failed_sources.append(own_end.recv())
+
                 # Close the pipe (both ends)
                 own_end.close()
 
@@ -266,6 +282,13 @@ async def create_tunnel_server(cfg: 'config.ConfigurationType', context: 'ssl.SS
     proxy.ns.total = 0
     proxy.ns.sent = 0
     proxy.ns.recv = 0
+    proxy.ns.handshake_failures = 0
+    proxy.ns.broker_errors = 0
+    proxy.ns.invalid_commands = 0
+    proxy.ns.malformed_commands = 0
+    proxy.ns.broker_requests = 0
+    proxy.ns.broker_latency = 0.0
+    proxy.bandwidth = None
     proxy.counter = 0
 
     loop = asyncio.get_running_loop()